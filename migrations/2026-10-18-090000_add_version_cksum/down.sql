@@ -0,0 +1 @@
+ALTER TABLE version DROP COLUMN cksum
@@ -0,0 +1 @@
+ALTER TABLE version DROP COLUMN created_at
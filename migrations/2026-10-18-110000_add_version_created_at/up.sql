@@ -0,0 +1 @@
+ALTER TABLE version ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT now()
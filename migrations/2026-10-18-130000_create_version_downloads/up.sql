@@ -0,0 +1,7 @@
+CREATE TABLE version_downloads (
+  version_id INTEGER NOT NULL,
+  date DATE DEFAULT CURRENT_DATE NOT NULL,
+  downloads INTEGER DEFAULT 0 NOT NULL,
+  primary key (version_id, date),
+  foreign key (version_id) references version(id) on delete cascade
+)
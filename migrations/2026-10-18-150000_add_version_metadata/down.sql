@@ -0,0 +1 @@
+ALTER TABLE version DROP COLUMN metadata, DROP COLUMN updated_at
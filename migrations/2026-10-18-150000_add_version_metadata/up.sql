@@ -0,0 +1 @@
+ALTER TABLE version ADD COLUMN metadata TEXT, ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT now()
@@ -0,0 +1 @@
+ALTER TABLE version DROP COLUMN missing_from_index
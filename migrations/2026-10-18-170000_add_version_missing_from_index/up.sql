@@ -0,0 +1 @@
+ALTER TABLE version ADD COLUMN missing_from_index BOOLEAN NOT NULL DEFAULT false
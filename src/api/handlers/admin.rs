@@ -0,0 +1,31 @@
+use std::sync::Arc;
+
+use crate::Application;
+
+use warp::reject::{custom, not_found};
+
+pub fn verify_storage(app: Arc<Application>) -> Result<impl warp::Reply, warp::Rejection> {
+    let entries = app.index.entries().map_err(custom)?;
+
+    let report = crate::verify::verify_storage(&entries, &*app.storage, None).map_err(custom)?;
+
+    Ok(warp::reply::json(&report))
+}
+
+pub fn replication(app: Arc<Application>) -> Result<impl warp::Reply, warp::Rejection> {
+    let replication = app.replication.as_ref().ok_or_else(not_found)?;
+
+    Ok(warp::reply::json(&replication.lag()))
+}
+
+pub fn cache(app: Arc<Application>) -> Result<impl warp::Reply, warp::Rejection> {
+    let cache = app.cache.as_ref().ok_or_else(not_found)?;
+
+    Ok(warp::reply::json(&cache.stats()))
+}
+
+pub fn squash_index(app: Arc<Application>) -> Result<impl warp::Reply, warp::Rejection> {
+    app.index.squash().map_err(custom)?;
+
+    Ok(warp::reply::json(&super::OK::new()))
+}
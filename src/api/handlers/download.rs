@@ -1,26 +1,319 @@
-use std::str::FromStr;
+use std::cmp;
+use std::io::{self, Read};
 use std::sync::Arc;
 
+use crate::models::{krate::Krate, version};
+use crate::storage::{parse_crate_path, DownloadMode};
 use crate::types::CrateName;
 use crate::Application;
 
+use hyper::Body;
 use semver::Version;
-use warp::http::Uri;
-use warp::reject::custom;
+use warp::http::{header, Response, StatusCode};
+use warp::path::Tail;
+use warp::reject::{custom, not_found};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Tarballs for a published version never change, so they can be cached
+// indefinitely.
+const CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
 
 pub fn download(
     crate_id: CrateName,
     version: Version,
+    range: Option<String>,
+    if_none_match: Option<String>,
     app: Arc<Application>,
-) -> Result<impl warp::Reply, warp::Rejection> {
+) -> Result<Response<Body>, warp::Rejection> {
+    match app.download_mode {
+        DownloadMode::Redirect => redirect(&crate_id, &version, &app),
+        DownloadMode::Proxy => proxy(&crate_id, &version, range, if_none_match, &app),
+    }
+}
+
+fn redirect(
+    crate_id: &CrateName,
+    version: &Version,
+    app: &Application,
+) -> Result<Response<Body>, warp::Rejection> {
     let redirect_url: String = app
         .storage
-        .get(&crate_id, &version.to_string())
+        .get(crate_id, &version.to_string())
         .map_err(custom)?;
 
     info!("Redirect URL: {}", redirect_url);
 
-    Ok(warp::redirect::redirect(
-        Uri::from_str(&redirect_url).map_err(custom)?,
-    ))
+    app.downloads.record(crate_id, &version.to_string());
+
+    Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header(header::LOCATION, redirect_url)
+        .body(Body::empty())
+        .map_err(custom)
+}
+
+fn proxy(
+    crate_id: &CrateName,
+    vers: &Version,
+    range: Option<String>,
+    if_none_match: Option<String>,
+    app: &Application,
+) -> Result<Response<Body>, warp::Rejection> {
+    let conn = app.pool.get().unwrap();
+
+    let krate = Krate::by_name(&conn, crate_id)
+        .map_err(custom)?
+        .ok_or_else(not_found)?;
+
+    let version = version::Version::by_crate_id_and_version(&conn, krate.id, &vers.to_string())
+        .map_err(custom)?
+        .ok_or_else(not_found)?;
+
+    let etag = version.cksum.as_ref().map(|cksum| format!("\"{}\"", cksum));
+
+    if let (Some(etag), Some(if_none_match)) = (&etag, &if_none_match) {
+        if etag_matches(etag, if_none_match) {
+            return Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(header::ETAG, etag.as_str())
+                .header(header::CACHE_CONTROL, CACHE_CONTROL)
+                .body(Body::empty())
+                .map_err(custom);
+        }
+    }
+
+    let object = app
+        .storage
+        .stream(crate_id, &version.vers)
+        .map_err(custom)?;
+
+    let mut builder = Response::builder();
+    builder
+        .header(header::CONTENT_TYPE, "application/gzip")
+        .header(header::CACHE_CONTROL, CACHE_CONTROL)
+        .header(header::ACCEPT_RANGES, "bytes");
+    if let Some(ref etag) = etag {
+        builder.header(header::ETAG, etag.as_str());
+    }
+
+    let range = range.map_or(ByteRange::Ignored, |range| {
+        parse_range(&range, object.length)
+    });
+    let (start, end) = match range {
+        ByteRange::Satisfiable(start, end) => {
+            builder.status(StatusCode::PARTIAL_CONTENT).header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, object.length),
+            );
+            (start, end)
+        }
+        ByteRange::Unsatisfiable => {
+            return builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", object.length))
+                .body(Body::empty())
+                .map_err(custom);
+        }
+        ByteRange::Ignored if object.length == 0 => {
+            return builder
+                .header(header::CONTENT_LENGTH, 0u64)
+                .body(Body::empty())
+                .map_err(custom);
+        }
+        ByteRange::Ignored => (0, object.length - 1),
+    };
+
+    let length = end - start + 1;
+
+    // Resumed or split downloads only count once, for their first range
+    if start == 0 {
+        app.downloads.record(crate_id, &version.vers);
+    }
+
+    let mut body = object.body;
+    io::copy(&mut body.by_ref().take(start), &mut io::sink()).map_err(custom)?;
+
+    let chunks = Chunks {
+        reader: body.take(length),
+        remaining: length,
+    };
+
+    builder
+        .header(header::CONTENT_LENGTH, length)
+        .body(Body::wrap_stream(futures::stream::iter_result(chunks)))
+        .map_err(custom)
+}
+
+/// Serves a tarball from a backend that keeps them on the local filesystem,
+/// which is where `redirect` sends clients for those backends, as their URLs
+/// are under `/local`. Tarballs are read through the backend so they're
+/// checked against their checksum.
+pub fn local(tail: Tail, app: Arc<Application>) -> Result<Response<Body>, warp::Rejection> {
+    let (name, vers) = parse_crate_path(tail.as_str()).ok_or_else(not_found)?;
+
+    if !app.storage.exists(&name, &vers).map_err(custom)? {
+        return Err(not_found());
+    }
+
+    let object = app.storage.stream(&name, &vers).map_err(custom)?;
+
+    let chunks = Chunks {
+        reader: object.body.take(object.length),
+        remaining: object.length,
+    };
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/gzip")
+        .header(header::CONTENT_LENGTH, object.length)
+        .body(Body::wrap_stream(futures::stream::iter_result(chunks)))
+        .map_err(custom)
+}
+
+/// Checks an `If-None-Match` header value against the ETag of a tarball.
+pub(crate) fn etag_matches(etag: &str, if_none_match: &str) -> bool {
+    if_none_match.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate == etag || candidate.trim_start_matches("W/") == etag
+    })
+}
+
+/// What to serve for a `Range` header.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// Inclusive start and end offsets of a single range.
+    Satisfiable(u64, u64),
+    /// A `bytes` range entirely past the end of the tarball.
+    Unsatisfiable,
+    /// Anything else, such as several ranges or another unit, which is
+    /// answered with the whole tarball.
+    Ignored,
+}
+
+/// Parses a single `bytes` range from a `Range` header. Headers that can't
+/// be parsed are ignored, as HTTP allows.
+fn parse_range(range: &str, length: u64) -> ByteRange {
+    let spec = range.trim().trim_start_matches("bytes=");
+    if spec.len() == range.trim().len() || spec.contains(',') {
+        return ByteRange::Ignored;
+    }
+
+    let mut parts = spec.splitn(2, '-');
+    let (start, end) = match (parts.next(), parts.next()) {
+        (Some(start), Some(end)) => (start.trim(), end.trim()),
+        _ => return ByteRange::Ignored,
+    };
+    let parse = |offset: &str| offset.parse::<u64>().ok();
+
+    let (start, end) = match (parse(start), parse(end)) {
+        // `bytes=-N` requests the last N bytes
+        (None, Some(suffix)) if start.is_empty() => {
+            if suffix == 0 || length == 0 {
+                return ByteRange::Unsatisfiable;
+            }
+            (length.saturating_sub(suffix), length - 1)
+        }
+        // `bytes=N-` requests everything from N onwards
+        (Some(start), None) if end.is_empty() => (start, length.saturating_sub(1)),
+        (Some(start), Some(end)) if start <= end => {
+            (start, cmp::min(end, length.saturating_sub(1)))
+        }
+        _ => return ByteRange::Ignored,
+    };
+
+    if start >= length {
+        return ByteRange::Unsatisfiable;
+    }
+
+    ByteRange::Satisfiable(start, end)
+}
+
+/// Reads a tarball in fixed size chunks so it can be streamed as a body.
+struct Chunks<R> {
+    reader: R,
+    remaining: u64,
+}
+
+impl<R: Read> Iterator for Chunks<R> {
+    type Item = Result<Vec<u8>, io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let mut buf = vec![0; cmp::min(self.remaining, CHUNK_SIZE as u64) as usize];
+        match self.reader.read(&mut buf) {
+            Ok(0) => {
+                self.remaining = 0;
+                Some(Err(io::ErrorKind::UnexpectedEof.into()))
+            }
+            Ok(n) => {
+                buf.truncate(n);
+                self.remaining -= n as u64;
+                Some(Ok(buf))
+            }
+            Err(err) => {
+                self.remaining = 0;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_byte_ranges() {
+        use super::ByteRange::*;
+
+        assert_eq!(parse_range("bytes=0-99", 1000), Satisfiable(0, 99));
+        assert_eq!(parse_range("bytes=500-", 1000), Satisfiable(500, 999));
+        assert_eq!(parse_range("bytes=-100", 1000), Satisfiable(900, 999));
+        assert_eq!(parse_range("bytes=900-2000", 1000), Satisfiable(900, 999));
+        assert_eq!(parse_range("bytes=-2000", 1000), Satisfiable(0, 999));
+
+        assert_eq!(parse_range("bytes=1000-", 1000), Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), Unsatisfiable);
+
+        assert_eq!(parse_range("bytes=50-10", 1000), Ignored);
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), Ignored);
+        assert_eq!(parse_range("items=0-1", 1000), Ignored);
+        assert_eq!(parse_range("bytes=abc", 1000), Ignored);
+    }
+
+    #[test]
+    fn match_etags() {
+        let etag = "\"abc\"";
+
+        assert!(etag_matches(etag, "\"abc\""));
+        assert!(etag_matches(etag, "\"xyz\", \"abc\""));
+        assert!(etag_matches(etag, "W/\"abc\""));
+        assert!(etag_matches(etag, "*"));
+        assert!(!etag_matches(etag, "\"xyz\""));
+    }
+
+    #[test]
+    fn read_chunks() {
+        let data = vec![1u8; CHUNK_SIZE + 10];
+        let chunks = Chunks {
+            reader: &data[..],
+            remaining: data.len() as u64,
+        }
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1].len(), 10);
+
+        let truncated = Chunks {
+            reader: &data[..10],
+            remaining: 20,
+        }
+        .collect::<Result<Vec<_>, _>>();
+
+        assert!(truncated.is_err());
+    }
 }
@@ -0,0 +1,218 @@
+use std::io::Read;
+use std::sync::Arc;
+use std::thread;
+
+use crate::error::Error;
+use crate::index::UploadPack;
+use crate::models::token::Token;
+use crate::Application;
+
+use bytes::Buf;
+use flate2::read::GzDecoder;
+use futures::sync::mpsc;
+use futures::{Sink, Stream};
+use hyper::Body;
+use serde::Deserialize;
+use warp::http::{header, Response, StatusCode};
+use warp::reject::{custom, not_found};
+
+// Chunks of the pack buffered ahead of a slow client
+const PACK_CHUNKS: usize = 16;
+
+/// Largest `git-upload-pack` request accepted, before and after it's
+/// decompressed. Fetching the index only ever needs a fraction of this.
+pub(crate) const MAX_REQUEST_SIZE: u64 = 10 * 1024 * 1024;
+
+#[derive(Deserialize)]
+pub struct InfoRefs {
+    service: String,
+}
+
+/// Advertises the refs of the hosted index, the first step of a clone or
+/// fetch over git's smart HTTP protocol.
+pub fn info_refs(
+    query: InfoRefs,
+    authorization: Option<String>,
+    app: Arc<Application>,
+) -> Result<Response<Body>, warp::Rejection> {
+    let git = app.index.hosted().ok_or_else(not_found)?;
+
+    // Pushes aren't supported, pallet is the only writer
+    if query.service != "git-upload-pack" {
+        return Err(not_found());
+    }
+
+    if !authorized(&app, authorization).map_err(custom)? {
+        return unauthorized();
+    }
+
+    let refs = git.advertise_refs().map_err(custom)?;
+
+    Response::builder()
+        .header(
+            header::CONTENT_TYPE,
+            "application/x-git-upload-pack-advertisement",
+        )
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::from(refs))
+        .map_err(custom)
+}
+
+/// Negotiates and sends the objects a git client is missing.
+pub fn upload_pack(
+    content_encoding: Option<String>,
+    authorization: Option<String>,
+    body: warp::body::FullBody,
+    app: Arc<Application>,
+) -> Result<Response<Body>, warp::Rejection> {
+    let git = app.index.hosted().ok_or_else(not_found)?;
+
+    if !authorized(&app, authorization).map_err(custom)? {
+        return unauthorized();
+    }
+
+    // git compresses larger requests. Reading one byte past the limit tells
+    // a request that's too large apart from one that fits exactly.
+    let mut request = Vec::new();
+    let result = match content_encoding.as_ref().map(String::as_str) {
+        Some("gzip") => GzDecoder::new(body.reader())
+            .take(MAX_REQUEST_SIZE + 1)
+            .read_to_end(&mut request),
+        _ => body.reader().read_to_end(&mut request),
+    };
+    result.map_err(|err| custom(Error::IO(err)))?;
+    if request.len() as u64 > MAX_REQUEST_SIZE {
+        return Err(custom(Error::GitProtocol("request too large".to_owned())));
+    }
+
+    let upload = git.upload_pack(&request).map_err(custom)?;
+
+    let body = if upload.has_pack() {
+        pack(upload, app.clone())
+    } else {
+        Body::from(upload.ack)
+    };
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-git-upload-pack-result")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(body)
+        .map_err(custom)
+}
+
+/// Streams the ack and pack of a fetch, building the pack in its own thread
+/// so a large one doesn't hold up the server.
+fn pack(upload: UploadPack, app: Arc<Application>) -> Body {
+    let (sender, receiver) = mpsc::channel::<Result<Vec<u8>, Error>>(PACK_CHUNKS);
+
+    thread::spawn(move || {
+        let mut sender = sender.wait();
+        if sender.send(Ok(upload.ack.clone())).is_err() {
+            return;
+        }
+
+        let git = match app.index.hosted() {
+            Some(git) => git,
+            None => return,
+        };
+        let result = git.write_pack(&upload, |data| sender.send(Ok(data.to_vec())).is_ok());
+
+        if let Err(err) = result {
+            warn!("Failed to send the index pack: {}", err);
+            let _ = sender.send(Err(err));
+        }
+    });
+
+    // The receiver never fails, errors are sent through it
+    Body::wrap_stream(receiver.then(|chunk| chunk.unwrap()))
+}
+
+/// Whether the request may fetch the index, over git or the sparse
+/// protocol. With `--hosted-index-auth` it
+/// has to carry an API token, either as is like cargo sends it or as the
+/// password of HTTP basic auth, which is what git credential helpers send.
+/// Other authorization schemes are refused.
+pub(crate) fn authorized(app: &Application, authorization: Option<String>) -> Result<bool, Error> {
+    if !app.hosted_index_auth {
+        return Ok(true);
+    }
+
+    let token = match authorization.as_ref().and_then(|value| token(value)) {
+        Some(token) => token,
+        None => return Ok(false),
+    };
+
+    let conn = app.pool.get()?;
+
+    match Token::by_token(&conn, &token)? {
+        Some(token) => Ok(token.owner(&conn)?.is_some()),
+        None => Ok(false),
+    }
+}
+
+/// The API token in an `Authorization` header value, if it's in one of the
+/// accepted forms.
+fn token(authorization: &str) -> Option<String> {
+    let token = if authorization.starts_with("Basic ") {
+        basic_auth_password(&authorization["Basic ".len()..])?
+    } else {
+        authorization.to_owned()
+    };
+
+    if crate::utils::is_token(&token) {
+        Some(token)
+    } else {
+        None
+    }
+}
+
+fn basic_auth_password(credentials: &str) -> Option<String> {
+    let credentials = String::from_utf8(base64::decode(credentials.trim()).ok()?).ok()?;
+    let mut parts = credentials.splitn(2, ':');
+    parts.next()?;
+    parts.next().map(str::to_owned)
+}
+
+/// Asks for credentials, which git clients only send once challenged.
+pub(crate) fn unauthorized() -> Result<Response<Body>, warp::Rejection> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(header::WWW_AUTHENTICATE, "Basic realm=\"pallet\"")
+        .body(Body::empty())
+        .map_err(custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_from_basic_auth() {
+        // `cargo:secret`
+        assert_eq!(
+            basic_auth_password("Y2FyZ286c2VjcmV0"),
+            Some("secret".to_owned())
+        );
+        // `secret`, without a username
+        assert_eq!(basic_auth_password("c2VjcmV0"), None);
+        assert_eq!(basic_auth_password("not base64!"), None);
+    }
+
+    #[test]
+    fn accepted_authorization() {
+        let token = "0123456789abcdef0123456789abcdef";
+
+        assert_eq!(super::token(token), Some(token.to_owned()));
+        assert_eq!(
+            super::token(&format!(
+                "Basic {}",
+                base64::encode(&format!("cargo:{}", token))
+            )),
+            Some(token.to_owned())
+        );
+
+        assert_eq!(super::token(&format!("Bearer {}", token)), None);
+        assert_eq!(super::token("Basic Y2FyZ286c2VjcmV0"), None);
+        assert_eq!(super::token("secret"), None);
+    }
+}
@@ -0,0 +1,164 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::Application;
+
+use chrono::{DateTime, Utc};
+use hyper::Body;
+use sha2::{Digest, Sha256};
+use warp::http::{header, Response, StatusCode};
+use warp::path::Tail;
+use warp::reject::{custom, not_found};
+
+// Index files change whenever a crate is published or yanked, so clients
+// always revalidate them.
+const CACHE_CONTROL: &str = "no-cache";
+
+/// Serves `config.json` for the sparse index protocol.
+pub fn config(
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    authorization: Option<String>,
+    app: Arc<Application>,
+) -> Result<Response<Body>, warp::Rejection> {
+    if !super::git::authorized(&app, authorization).map_err(custom)? {
+        return super::git::unauthorized();
+    }
+
+    serve(
+        Path::new("config.json"),
+        "application/json",
+        if_none_match,
+        if_modified_since,
+        &app,
+    )
+}
+
+/// Serves the index file of a crate for the sparse index protocol, at the
+/// same path as in the git index.
+pub fn file(
+    tail: Tail,
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    authorization: Option<String>,
+    app: Arc<Application>,
+) -> Result<Response<Body>, warp::Rejection> {
+    let path = Path::new(tail.as_str());
+    if crate::index::crate_name(path).is_none() {
+        return Err(not_found());
+    }
+
+    if !super::git::authorized(&app, authorization).map_err(custom)? {
+        return super::git::unauthorized();
+    }
+
+    serve(
+        path,
+        "text/plain; charset=utf-8",
+        if_none_match,
+        if_modified_since,
+        &app,
+    )
+}
+
+fn serve(
+    path: &Path,
+    content_type: &str,
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    app: &Application,
+) -> Result<Response<Body>, warp::Rejection> {
+    let file = app
+        .index
+        .file(path)
+        .map_err(custom)?
+        .ok_or_else(not_found)?;
+
+    let etag = format!("\"{:x}\"", Sha256::digest(&file.content));
+    let last_modified = file
+        .last_modified
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string();
+
+    let mut builder = Response::builder();
+    builder
+        .header(header::ETAG, etag.as_str())
+        .header(header::LAST_MODIFIED, last_modified.as_str())
+        .header(header::CACHE_CONTROL, CACHE_CONTROL);
+
+    if not_modified(&etag, file.last_modified, if_none_match, if_modified_since) {
+        return builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .map_err(custom);
+    }
+
+    builder
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from(file.content))
+        .map_err(custom)
+}
+
+/// Evaluates the conditional request headers, where `If-None-Match` takes
+/// precedence over `If-Modified-Since` as it does in RFC 7232.
+fn not_modified(
+    etag: &str,
+    modified: DateTime<Utc>,
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+) -> bool {
+    if let Some(if_none_match) = if_none_match {
+        return super::download::etag_matches(etag, &if_none_match);
+    }
+
+    match if_modified_since.and_then(|since| DateTime::parse_from_rfc2822(&since).ok()) {
+        // HTTP dates only have second precision
+        Some(since) => modified.timestamp() <= since.timestamp(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    #[test]
+    fn conditional_requests() {
+        let modified = Utc.ymd(2026, 10, 18).and_hms(12, 0, 0);
+        let etag = "\"abc\"";
+
+        assert!(!not_modified(etag, modified, None, None));
+        assert!(not_modified(
+            etag,
+            modified,
+            Some("\"abc\"".to_owned()),
+            None
+        ));
+        assert!(!not_modified(
+            etag,
+            modified,
+            Some("\"xyz\"".to_owned()),
+            Some("Sun, 18 Oct 2026 12:00:00 GMT".to_owned())
+        ));
+        assert!(not_modified(
+            etag,
+            modified,
+            None,
+            Some("Sun, 18 Oct 2026 12:00:00 GMT".to_owned())
+        ));
+        assert!(!not_modified(
+            etag,
+            modified,
+            None,
+            Some("Sun, 18 Oct 2026 11:59:59 GMT".to_owned())
+        ));
+        assert!(!not_modified(
+            etag,
+            modified,
+            None,
+            Some("yesterday".to_owned())
+        ));
+    }
+}
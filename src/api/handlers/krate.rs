@@ -0,0 +1,157 @@
+use std::sync::Arc;
+
+use crate::models::{krate::Krate, version, version_downloads::VersionDownloads};
+use crate::types::CrateName;
+use crate::Application;
+
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
+use semver::Version;
+use serde::Serialize;
+use warp::reject::{custom, not_found};
+
+// How far back the daily download history goes, the same as crates.io.
+const DOWNLOAD_HISTORY_DAYS: i64 = 90;
+
+#[derive(Debug, Serialize)]
+pub struct CrateResponse {
+    #[serde(rename = "crate")]
+    krate: CrateInfo,
+    versions: Vec<VersionInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CrateInfo {
+    name: String,
+    description: Option<String>,
+    max_version: Option<String>,
+    downloads: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionResponse {
+    version: VersionInfo,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    #[serde(rename = "crate")]
+    krate: String,
+    num: String,
+    yanked: bool,
+    downloads: i64,
+    created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DownloadsResponse {
+    version_downloads: Vec<VersionDownload>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionDownload {
+    version: String,
+    date: NaiveDate,
+    downloads: i32,
+}
+
+pub fn show(
+    crate_id: CrateName,
+    app: Arc<Application>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let conn = app.pool.get().unwrap();
+
+    let krate = Krate::by_name(&conn, &crate_id)
+        .map_err(custom)?
+        .ok_or_else(not_found)?;
+
+    let totals = VersionDownloads::totals_by_crate_id(&conn, krate.id).map_err(custom)?;
+
+    let mut versions = version::Version::by_crate_id(&conn, krate.id)
+        .map_err(custom)?
+        .into_iter()
+        .map(|version| {
+            let downloads = totals.get(&version.id).cloned().unwrap_or(0);
+            (Version::parse(&version.vers).ok(), version, downloads)
+        })
+        .collect::<Vec<_>>();
+    versions.sort_by(|a, b| b.0.cmp(&a.0));
+
+    let max_version = versions
+        .iter()
+        .find(|(_, version, _)| !version.yanked)
+        .map(|(_, version, _)| version.vers.to_owned());
+
+    Ok(warp::reply::json(&CrateResponse {
+        krate: CrateInfo {
+            name: krate.name.to_string(),
+            description: krate.description,
+            max_version,
+            downloads: totals.values().sum(),
+        },
+        versions: versions
+            .into_iter()
+            .map(|(_, version, downloads)| version_info(&crate_id, version, downloads))
+            .collect(),
+    }))
+}
+
+pub fn show_version(
+    crate_id: CrateName,
+    vers: Version,
+    app: Arc<Application>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let conn = app.pool.get().unwrap();
+
+    let krate = Krate::by_name(&conn, &crate_id)
+        .map_err(custom)?
+        .ok_or_else(not_found)?;
+
+    let version = version::Version::by_crate_id_and_version(&conn, krate.id, &vers.to_string())
+        .map_err(custom)?
+        .ok_or_else(not_found)?;
+
+    let downloads = VersionDownloads::totals_by_crate_id(&conn, krate.id)
+        .map_err(custom)?
+        .get(&version.id)
+        .cloned()
+        .unwrap_or(0);
+
+    Ok(warp::reply::json(&VersionResponse {
+        version: version_info(&crate_id, version, downloads),
+    }))
+}
+
+pub fn downloads(
+    crate_id: CrateName,
+    app: Arc<Application>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let conn = app.pool.get().unwrap();
+
+    let krate = Krate::by_name(&conn, &crate_id)
+        .map_err(custom)?
+        .ok_or_else(not_found)?;
+
+    let since = Utc::today().naive_utc() - Duration::days(DOWNLOAD_HISTORY_DAYS);
+
+    let version_downloads = VersionDownloads::by_crate_id_since(&conn, krate.id, since)
+        .map_err(custom)?
+        .into_iter()
+        .map(|(version, downloads)| VersionDownload {
+            version,
+            date: downloads.date,
+            downloads: downloads.downloads,
+        })
+        .collect();
+
+    Ok(warp::reply::json(&DownloadsResponse { version_downloads }))
+}
+
+fn version_info(crate_id: &CrateName, version: version::Version, downloads: i64) -> VersionInfo {
+    VersionInfo {
+        krate: crate_id.to_string(),
+        num: version.vers,
+        yanked: version.yanked,
+        downloads,
+        created_at: version.created_at,
+    }
+}
@@ -1,4 +1,8 @@
+pub mod admin;
 pub mod download;
+pub mod git;
+pub mod index;
+pub mod krate;
 pub mod me;
 pub mod owners;
 pub mod publish;
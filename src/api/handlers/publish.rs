@@ -33,7 +33,9 @@ pub struct CrateUpload {
     license: Option<String>,
     license_file: Option<String>,
     repository: Option<String>,
-    links: Option<Vec<String>>,
+    links: Option<String>,
+    #[serde(default)]
+    rust_version: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -107,14 +109,21 @@ pub fn publish(
     // Check each dependency isn't used a registry that isn't allowed
     app.dependency_registry_allowed(&deps).map_err(custom)?;
 
+    // Cargo versions that don't understand `dep:` and `?/` would reject the
+    // entry, so those features are kept apart.
+    let (features, features2) = Metadata::split_features(crate_upload.features);
+
     let metadata = Metadata {
         name: crate_upload.name,
         vers: crate_upload.vers,
         deps,
         cksum: format!("{:x}", hash),
-        features: crate_upload.features,
+        features,
         yanked: false,
-        links: None,
+        links: crate_upload.links,
+        v: features2.as_ref().map(|_| 2),
+        features2,
+        rust_version: crate_upload.rust_version,
     };
 
     let krate = match Krate::by_name(&conn, &metadata.name).map_err(custom)? {
@@ -146,9 +155,10 @@ pub fn publish(
         krate_id: krate.id,
         vers: &metadata.vers.to_string(),
         yanked: false,
+        cksum: &metadata.cksum,
     };
 
-    new_version.save(&conn).map_err(custom)?;
+    let version = new_version.save(&conn).map_err(custom)?;
 
     // Upload to storage
     app.storage
@@ -156,7 +166,11 @@ pub fn publish(
         .map_err(custom)?;
 
     // Save to registry
-    crate::add_crate(&app, &metadata).map_err(custom)?;
+    app.index.add(&metadata, &owner.login).map_err(custom)?;
+
+    // Keep the entry so the index can be rebuilt from the database
+    let entry = serde_json::to_string(&metadata).map_err(custom)?;
+    version.set_metadata(&conn, &entry).map_err(custom)?;
 
     let resp = SuccessfulResponse::new();
 
@@ -44,7 +44,9 @@ fn do_yank(
         .map_err(custom)?
         .ok_or_else(not_found)?;
 
-    crate::yank_crate(&app, crate_id, vers, yanked).map_err(custom)?;
+    app.index
+        .yank(crate_id, vers, yanked, &owner.login)
+        .map_err(custom)?;
 
     version.set_yanked(&conn, yanked).map_err(custom)?;
 
@@ -32,6 +32,17 @@ pub(crate) fn auth(app: Arc<Application>) -> BoxedFilter<(Owner,)> {
         .boxed()
 }
 
+/// Only lets requests through that carry the `--admin-token`.
+pub(crate) fn admin(app: Arc<Application>) -> BoxedFilter<()> {
+    warp::header::<String>("authorization")
+        .and_then(move |token: String| match app.admin_token {
+            Some(ref admin_token) if *admin_token == token => Ok(()),
+            _ => Err(custom(Error::Unauthorized)),
+        })
+        .untuple_one()
+        .boxed()
+}
+
 pub(crate) fn error_handler(err: Rejection) -> Result<impl Reply, Rejection> {
     if let Some(ref err) = err.find_cause::<Error>() {
         match err {
@@ -6,6 +6,7 @@ use crate::types::CrateName;
 use crate::Application;
 
 use futures::sync::oneshot;
+use futures::Future;
 use semver::Version;
 use warp::{path, Filter};
 
@@ -19,6 +20,19 @@ pub fn server(addr: impl Into<SocketAddr> + 'static, application: Arc<Applicatio
 
     let api_endpoint = path!("api" / "v1");
 
+    let index_endpoint = path!("index");
+    let index_config_endpoint = index_endpoint
+        .and(path!("config.json"))
+        .and(warp::path::end());
+
+    let git_index_endpoint = path!("git" / "index");
+    let git_info_refs_endpoint = git_index_endpoint
+        .and(path!("info" / "refs"))
+        .and(warp::path::end());
+    let git_upload_pack_endpoint = git_index_endpoint
+        .and(path!("git-upload-pack"))
+        .and(warp::path::end());
+
     let crates_endpoint = api_endpoint.and(path!("crates"));
 
     let publish_endpoint = crates_endpoint.and(path!("new")).and(warp::path::end());
@@ -26,6 +40,10 @@ pub fn server(addr: impl Into<SocketAddr> + 'static, application: Arc<Applicatio
     let crate_id = crates_endpoint.and(warp::path::param::<CrateName>());
     let crate_version = crate_id.and(warp::path::param::<Version>());
 
+    let crate_endpoint = crate_id.and(warp::path::end());
+    let version_endpoint = crate_version.and(warp::path::end());
+    let crate_downloads_endpoint = crate_id.and(path!("downloads")).and(warp::path::end());
+
     let download_endpoint = crate_version.and(path!("download")).and(warp::path::end());
 
     let yank_endpoint = crate_version.and(path!("yank")).and(warp::path::end());
@@ -39,6 +57,24 @@ pub fn server(addr: impl Into<SocketAddr> + 'static, application: Arc<Applicatio
 
     let token_endpoint = api_endpoint.and(path!("token"));
 
+    let admin_endpoint = api_endpoint.and(path!("admin"));
+
+    let verify_storage_endpoint = admin_endpoint
+        .and(path!("storage" / "verify"))
+        .and(warp::path::end());
+
+    let replication_endpoint = admin_endpoint
+        .and(path!("storage" / "replication"))
+        .and(warp::path::end());
+
+    let cache_endpoint = admin_endpoint
+        .and(path!("storage" / "cache"))
+        .and(warp::path::end());
+
+    let squash_index_endpoint = admin_endpoint
+        .and(path!("index" / "squash"))
+        .and(warp::path::end());
+
     let new_owner_endpoint = api_endpoint
         .and(path!("owners" / "new"))
         .and(warp::path::end());
@@ -55,9 +91,29 @@ pub fn server(addr: impl Into<SocketAddr> + 'static, application: Arc<Applicatio
     // Download `GET /api/v1/crates/:crate_id/:version/download`
     let crates_download = warp::get2()
         .and(download_endpoint)
+        .and(warp::header::optional::<String>("range"))
+        .and(warp::header::optional::<String>("if-none-match"))
         .and(app.clone())
         .and_then(handlers::download::download);
 
+    // Crate `GET /api/v1/crates/:crate_id`
+    let crates_show = warp::get2()
+        .and(crate_endpoint)
+        .and(app.clone())
+        .and_then(handlers::krate::show);
+
+    // Version `GET /api/v1/crates/:crate_id/:version`
+    let crates_show_version = warp::get2()
+        .and(version_endpoint)
+        .and(app.clone())
+        .and_then(handlers::krate::show_version);
+
+    // Downloads `GET /api/v1/crates/:crate_id/downloads`
+    let crates_downloads = warp::get2()
+        .and(crate_downloads_endpoint)
+        .and(app.clone())
+        .and_then(handlers::krate::downloads);
+
     // Yank `DELETE /api/v1/crates/:crate_id/:version/yank`
     let crates_yank = warp::delete2()
         .and(middleware::auth(application.clone()))
@@ -101,6 +157,45 @@ pub fn server(addr: impl Into<SocketAddr> + 'static, application: Arc<Applicatio
         .and(warp::query::<handlers::search::SearchOptions>())
         .and_then(handlers::search::search);
 
+    // Sparse index config `GET /index/config.json`
+    let index_config = warp::get2()
+        .and(index_config_endpoint)
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::header::optional::<String>("if-modified-since"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(app.clone())
+        .and_then(handlers::index::config);
+
+    // Sparse index file `GET /index/:prefix/:crate_id`
+    let index_file = warp::get2()
+        .and(index_endpoint)
+        .and(warp::path::tail())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::header::optional::<String>("if-modified-since"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(app.clone())
+        .and_then(handlers::index::file);
+
+    // Hosted git index refs `GET /git/index/info/refs?service=git-upload-pack`
+    let git_info_refs = warp::get2()
+        .and(git_info_refs_endpoint)
+        .and(warp::query::<handlers::git::InfoRefs>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(app.clone())
+        .and_then(handlers::git::info_refs);
+
+    // Hosted git index fetch `POST /git/index/git-upload-pack`
+    let git_upload_pack = warp::post2()
+        .and(git_upload_pack_endpoint)
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::content_length_limit(
+            handlers::git::MAX_REQUEST_SIZE,
+        ))
+        .and(warp::body::concat())
+        .and(app.clone())
+        .and_then(handlers::git::upload_pack);
+
     // Me `GET /me`
     let me = warp::get2().and(me_endpoint).map(handlers::me::me);
 
@@ -127,8 +222,39 @@ pub fn server(addr: impl Into<SocketAddr> + 'static, application: Arc<Applicatio
         .and(app.clone())
         .and_then(handlers::owners::new);
 
+    // Verify Storage `GET /api/v1/admin/storage/verify`
+    let verify_storage = warp::get2()
+        .and(middleware::admin(application.clone()))
+        .and(verify_storage_endpoint)
+        .and(app.clone())
+        .and_then(handlers::admin::verify_storage);
+
+    // Replication `GET /api/v1/admin/storage/replication`
+    let replication = warp::get2()
+        .and(middleware::admin(application.clone()))
+        .and(replication_endpoint)
+        .and(app.clone())
+        .and_then(handlers::admin::replication);
+
+    // Cache `GET /api/v1/admin/storage/cache`
+    let cache = warp::get2()
+        .and(middleware::admin(application.clone()))
+        .and(cache_endpoint)
+        .and(app.clone())
+        .and_then(handlers::admin::cache);
+
+    // Squash Index `POST /api/v1/admin/index/squash`
+    let squash_index = warp::post2()
+        .and(middleware::admin(application.clone()))
+        .and(squash_index_endpoint)
+        .and(app.clone())
+        .and_then(handlers::admin::squash_index);
+
     let api = crates_new
         .or(crates_download)
+        .or(crates_show)
+        .or(crates_show_version)
+        .or(crates_downloads)
         .or(crates_yank)
         .or(crates_unyank)
         .or(owners_list)
@@ -136,9 +262,17 @@ pub fn server(addr: impl Into<SocketAddr> + 'static, application: Arc<Applicatio
         .or(owners_remove)
         .or(search)
         .or(me)
+        .or(index_config)
+        .or(index_file)
+        .or(git_info_refs)
+        .or(git_upload_pack)
         .or(token_add)
         .or(token_remove)
         .or(new_owner)
+        .or(verify_storage)
+        .or(replication)
+        .or(cache)
+        .or(squash_index)
         .recover(middleware::error_handler);
 
     let (tx, rx) = oneshot::channel();
@@ -149,14 +283,30 @@ pub fn server(addr: impl Into<SocketAddr> + 'static, application: Arc<Applicatio
     })
     .unwrap();
 
-    #[cfg(feature = "local")]
-    let (_addr, server) = warp::serve(
-        api.or(warp::path("local").and(warp::fs::dir(application.storage.base_path()))),
-    )
-    .bind_with_graceful_shutdown(addr, rx);
-    #[cfg(not(feature = "local"))]
-    let (_addr, server) = warp::serve(api).bind_with_graceful_shutdown(addr, rx);
+    // Crates stored on the local filesystem are served by pallet itself.
+    match application.storage.base_path() {
+        Some(_) => {
+            let local = warp::get2()
+                .and(warp::path("local"))
+                .and(warp::path::tail())
+                .and(app.clone())
+                .and_then(handlers::download::local)
+                .recover(middleware::error_handler);
+
+            let (_addr, server) = warp::serve(api.or(local)).bind_with_graceful_shutdown(addr, rx);
+            run(server);
+        }
+        None => {
+            let (_addr, server) = warp::serve(api).bind_with_graceful_shutdown(addr, rx);
+            run(server);
+        }
+    }
+}
 
+fn run<F>(server: F)
+where
+    F: Future<Item = (), Error = ()> + Send + 'static,
+{
     tokio::run(futures::future::lazy(move || {
         warp::spawn(server);
         Ok(())
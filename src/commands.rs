@@ -1,6 +1,12 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 
 use crate::error::Error;
+use crate::index::{self, Index, IndexMode};
+use crate::models::version::Version;
+use crate::repository::{Repository, SigningFormat};
+use crate::storage::{self, DownloadMode, MasterKey, StorageKind, StorageLocation};
 
 use structopt::StructOpt;
 
@@ -13,12 +19,45 @@ pub enum Commands {
     /// Serves the HTTP API
     #[structopt(name = "server")]
     Server(Server),
+    /// Checks the stored crates against the checksums in the index
+    #[structopt(name = "verify-storage")]
+    VerifyStorage(VerifyStorage),
+    /// Copies every crate from one storage backend to another
+    #[structopt(name = "migrate-storage")]
+    MigrateStorage(MigrateStorage),
+    /// Deletes version rows and crates left behind by failed publishes
+    #[structopt(name = "gc")]
+    Gc(Gc),
+    /// Re-wraps every stored crate under a new encryption key
+    #[structopt(name = "rotate-storage-key")]
+    RotateStorageKey(RotateStorageKey),
+    /// Regenerates the git index from the database and force pushes it
+    #[structopt(name = "rebuild-index")]
+    RebuildIndex(RebuildIndex),
+    /// Squashes the history of the git index into a single commit
+    #[structopt(name = "squash-index")]
+    SquashIndex(SquashIndex),
+    /// Checks the database, git index and storage against each other
+    #[structopt(name = "fsck")]
+    Fsck(Fsck),
+    /// Creates the git index with a generated `config.json`, or checks the
+    /// `config.json` of an existing one
+    #[structopt(name = "init-index")]
+    InitIndex(InitIndex),
 }
 
 impl Commands {
     pub fn run(&self) -> Result<(), Error> {
         match *self {
             Commands::Server(ref cmd) => cmd.run(),
+            Commands::VerifyStorage(ref cmd) => cmd.run(),
+            Commands::MigrateStorage(ref cmd) => cmd.run(),
+            Commands::Gc(ref cmd) => cmd.run(),
+            Commands::RotateStorageKey(ref cmd) => cmd.run(),
+            Commands::RebuildIndex(ref cmd) => cmd.run(),
+            Commands::SquashIndex(ref cmd) => cmd.run(),
+            Commands::Fsck(ref cmd) => cmd.run(),
+            Commands::InitIndex(ref cmd) => cmd.run(),
         }
     }
 }
@@ -31,18 +70,46 @@ pub struct Server {
     /// URL of database.
     #[structopt(long = "db-url", env = "DB_URL")]
     pub db_url: String,
-    #[cfg(feature = "local")]
+    /// How crates are downloaded, either `redirect` to the storage backend
+    /// or `proxy` through pallet
+    #[structopt(
+        long = "download-mode",
+        env = "DOWNLOAD_MODE",
+        default_value = "redirect"
+    )]
+    pub download_mode: DownloadMode,
+    /// Seconds between writing batched download counts to the database
+    #[structopt(
+        long = "downloads-flush-interval",
+        env = "DOWNLOADS_FLUSH_INTERVAL",
+        default_value = "10"
+    )]
+    pub downloads_flush_interval: u64,
     #[structopt(flatten)]
-    pub local_opts: LocalOpts,
-    #[cfg(feature = "s3")]
+    pub storage_opts: StorageOpts,
+    /// Storage backend to keep a second copy of every crate in, either
+    /// `local:PATH` or `s3:BUCKET[/PREFIX]`
+    #[structopt(long = "replica", env = "REPLICA")]
+    pub replica: Option<StorageLocation>,
+    /// Seconds between retrying writes to the replica that failed
+    #[structopt(
+        long = "replica-retry-interval",
+        env = "REPLICA_RETRY_INTERVAL",
+        default_value = "30"
+    )]
+    pub replica_retry_interval: u64,
+    /// Directory to cache downloaded crates in, which is disabled if unset
+    #[structopt(long = "cache-path", env = "CACHE_PATH")]
+    pub cache_path: Option<PathBuf>,
+    /// Max size of the download cache in bytes
+    #[structopt(long = "cache-size", env = "CACHE_SIZE", default_value = "1073741824")]
+    pub cache_size: u64,
     #[structopt(flatten)]
-    pub s3_opts: S3Opts,
-    /// Index location, e.g. git@github.com:nylar/private-registry.git
-    #[structopt(long = "index-location", env = "INDEX_LOCATION")]
-    pub index_location: String,
-    /// Checkout path
-    #[structopt(long = "checkout-path", env = "CHECKOUT_PATH")]
-    pub checkout_path: Option<PathBuf>,
+    pub index_opts: IndexOpts,
+    /// Hours between squashing the history of the git index, which is
+    /// disabled if unset
+    #[structopt(long = "index-squash-interval", env = "INDEX_SQUASH_INTERVAL")]
+    pub index_squash_interval: Option<u64>,
     /// Max upload size in bytes.
     #[structopt(
         long = "max-upload-size",
@@ -50,46 +117,659 @@ pub struct Server {
         default_value = "10485760"
     )]
     pub max_upload_size: u64,
+    /// Token required to access the admin API, which is disabled if unset
+    #[structopt(long = "admin-token", env = "ADMIN_TOKEN")]
+    pub admin_token: Option<String>,
+    /// Require an API token to fetch the index in hosted mode
+    #[structopt(long = "hosted-index-auth")]
+    pub hosted_index_auth: bool,
 }
 
 impl Command for Server {
     fn run(&self) -> Result<(), Error> {
         use std::net::SocketAddr;
-        use std::sync::Arc;
 
-        let app = crate::Application::new(&self)?;
+        let app = Arc::new(crate::Application::new(&self)?);
 
         let addr = format!("0.0.0.0:{}", self.port)
             .parse::<SocketAddr>()
             .unwrap();
 
-        crate::api::server(addr, Arc::new(app));
+        crate::api::server(addr, app.clone());
+
+        // Don't lose the downloads counted since the last flush.
+        app.downloads.flush(&*app.pool.get()?)?;
+
+        Ok(())
+    }
+}
+
+#[derive(StructOpt)]
+pub struct VerifyStorage {
+    /// URL of database, required in database index mode
+    #[structopt(long = "db-url", env = "DB_URL")]
+    pub db_url: Option<String>,
+    #[structopt(flatten)]
+    pub index_opts: IndexOpts,
+    #[structopt(flatten)]
+    pub storage_opts: StorageOpts,
+    /// Storage backend to restore missing or corrupted crates from, either
+    /// `local:PATH` or `s3:BUCKET[/PREFIX]`
+    #[structopt(long = "repair-from")]
+    pub repair_from: Option<StorageLocation>,
+    /// Output format, either `text` or `json`
+    #[structopt(long = "format", default_value = "text")]
+    pub format: OutputFormat,
+}
+
+impl Command for VerifyStorage {
+    fn run(&self) -> Result<(), Error> {
+        let index = open_index(&self.index_opts, self.db_url.as_ref())?;
+
+        let storage = storage::new(&self.storage_opts)?;
+
+        let repair_from = match self.repair_from {
+            Some(ref location) => Some(location.open(
+                &self.storage_opts.s3_opts,
+                &self.storage_opts.encryption_opts,
+            )?),
+            None => None,
+        };
+
+        let report = crate::verify::verify_storage(
+            &index.entries()?,
+            &*storage,
+            repair_from.as_ref().map(|storage| &**storage),
+        )?;
+
+        match self.format {
+            OutputFormat::Text => print!("{}", report),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+        }
+
+        if !report.is_ok() {
+            return Err(Error::CheckFailed("verify-storage"));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(StructOpt)]
+pub struct MigrateStorage {
+    /// URL of database, required in database index mode
+    #[structopt(long = "db-url", env = "DB_URL")]
+    pub db_url: Option<String>,
+    #[structopt(flatten)]
+    pub index_opts: IndexOpts,
+    /// Storage backend to copy crates from, either `local:PATH` or
+    /// `s3:BUCKET[/PREFIX]`
+    #[structopt(long = "from")]
+    pub from: StorageLocation,
+    /// Storage backend to copy crates to, either `local:PATH` or
+    /// `s3:BUCKET[/PREFIX]`
+    #[structopt(long = "to")]
+    pub to: StorageLocation,
+    #[structopt(flatten)]
+    pub s3_opts: S3Opts,
+    #[structopt(flatten)]
+    pub encryption_opts: EncryptionOpts,
+    /// Number of crates to copy concurrently
+    #[structopt(long = "jobs", default_value = "4")]
+    pub jobs: usize,
+}
+
+impl Command for MigrateStorage {
+    fn run(&self) -> Result<(), Error> {
+        let index = open_index(&self.index_opts, self.db_url.as_ref())?;
+
+        let from = self.from.open(&self.s3_opts, &self.encryption_opts)?;
+        let to = self.to.open(&self.s3_opts, &self.encryption_opts)?;
+
+        let summary = crate::migrate::migrate_storage(index.entries()?, from, to, self.jobs);
+
+        print!("{}", summary);
+
+        if !summary.failed.is_empty() {
+            return Err(Error::CheckFailed("migrate-storage"));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(StructOpt)]
+pub struct Gc {
+    /// URL of database.
+    #[structopt(long = "db-url", env = "DB_URL")]
+    pub db_url: String,
+    #[structopt(flatten)]
+    pub index_opts: IndexOpts,
+    #[structopt(flatten)]
+    pub storage_opts: StorageOpts,
+    /// Only report what would be deleted
+    #[structopt(long = "dry-run")]
+    pub dry_run: bool,
+    /// Hours an orphan has to exist for before it's deleted
+    #[structopt(long = "grace-period", default_value = "24")]
+    pub grace_period: i64,
+}
+
+impl Command for Gc {
+    fn run(&self) -> Result<(), Error> {
+        let pool = crate::make_pool(&self.db_url)?;
+        let conn = pool.get()?;
+
+        let index = index::open(&self.index_opts, Some(pool.clone()))?;
+
+        let storage = storage::new(&self.storage_opts)?;
+
+        let cutoff = chrono::Utc::now() - chrono::Duration::hours(self.grace_period);
+
+        let plan = crate::gc::plan(
+            &index.entries()?,
+            Version::all_with_crate_name(&conn)?,
+            &*storage,
+            cutoff,
+        )?;
+
+        print!("{}", plan);
+
+        if !self.dry_run && !plan.is_empty() {
+            crate::gc::collect(&plan, &conn, &*storage)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(StructOpt)]
+pub struct RotateStorageKey {
+    /// Storage to rotate, with the current key if any
+    #[structopt(flatten)]
+    pub storage_opts: StorageOpts,
+    /// Hex encoded 256-bit master key to re-wrap stored crates with
+    #[structopt(long = "new-encryption-key", env = "NEW_ENCRYPTION_KEY")]
+    pub new_encryption_key: Option<String>,
+    /// File containing the hex encoded 256-bit master key to re-wrap stored
+    /// crates with
+    #[structopt(long = "new-encryption-key-file", env = "NEW_ENCRYPTION_KEY_FILE")]
+    pub new_encryption_key_file: Option<PathBuf>,
+}
+
+impl Command for RotateStorageKey {
+    fn run(&self) -> Result<(), Error> {
+        let new = MasterKey::from_opts(&EncryptionOpts {
+            encryption_key: self.new_encryption_key.clone(),
+            encryption_key_file: self.new_encryption_key_file.clone(),
+            old_encryption_key: None,
+            old_encryption_key_file: None,
+        })?
+        .ok_or(Error::MissingStorageOption("new-encryption-key"))?;
+
+        // A rotation interrupted after the server was restarted with the new
+        // key leaves tarballs wrapped by either of the earlier keys.
+        let encryption_opts = &self.storage_opts.encryption_opts;
+        let old = MasterKey::from_opts(encryption_opts)?
+            .into_iter()
+            .chain(MasterKey::old_from_opts(encryption_opts)?)
+            .collect::<Vec<_>>();
+
+        // Rotation works on the raw envelopes, so it needs the backend
+        // without the encryption wrapper.
+        let storage = storage::backend(&self.storage_opts)?;
+
+        let rotation = storage::encrypted::rotate(&*storage, &old, &new)?;
+
+        print!("{}", rotation);
+
+        if !rotation.failed.is_empty() {
+            return Err(Error::CheckFailed("rotate-storage-key"));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(StructOpt)]
+pub struct RebuildIndex {
+    /// URL of database.
+    #[structopt(long = "db-url", env = "DB_URL")]
+    pub db_url: String,
+    #[structopt(flatten)]
+    pub index_opts: IndexOpts,
+    /// File to take `config.json` from, instead of generating it from
+    /// `--api-url`
+    #[structopt(long = "config")]
+    pub config: Option<PathBuf>,
+}
+
+impl Command for RebuildIndex {
+    fn run(&self) -> Result<(), Error> {
+        let index_location = self
+            .index_opts
+            .index_location
+            .as_ref()
+            .ok_or(Error::MissingIndexOption("index-location"))?;
+
+        let config = match (&self.config, &self.index_opts.api_url) {
+            (Some(path), _) => std::fs::read(path)?,
+            (None, Some(api_url)) => index::config_json(api_url, &[])?,
+            (None, None) => return Err(Error::MissingIndexOption("api-url")),
+        };
+
+        let pool = crate::make_pool(&self.db_url)?;
+        let conn = pool.get()?;
+
+        let rebuild = crate::rebuild::plan(&Version::all_with_crate_name(&conn)?)?;
+
+        // The checkout has to be empty, as everything in it is committed.
+        let checkout_path = empty_checkout_path(&self.index_opts)?;
+
+        let repo = Repository::init(index_location, &checkout_path, &self.index_opts.git_opts)?;
+        crate::rebuild::write(&rebuild, &config, &repo)?;
+
+        print!("{}", rebuild);
+
+        Ok(())
+    }
+}
+
+/// `--checkout-path`, which has to be empty, or a temporary directory.
+fn empty_checkout_path(opts: &IndexOpts) -> Result<PathBuf, Error> {
+    match opts.checkout_path {
+        Some(ref checkout_path) => {
+            if checkout_path.exists() && checkout_path.read_dir()?.next().is_some() {
+                return Err(Error::IO(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("{} isn't empty", checkout_path.display()),
+                )));
+            }
+            Ok(checkout_path.to_owned())
+        }
+        None => Ok(tempfile::TempDir::new()?.into_path()),
+    }
+}
+
+#[derive(StructOpt)]
+pub struct InitIndex {
+    #[structopt(flatten)]
+    pub index_opts: IndexOpts,
+    /// Port pallet serves the HTTP API on, used for the URLs in
+    /// `config.json` if `--api-url` isn't set
+    #[structopt(long = "port", env = "PORT")]
+    pub port: Option<u16>,
+    /// Registry crates may depend on besides crates.io and this one, can be
+    /// repeated
+    #[structopt(long = "allowed-registry")]
+    pub allowed_registries: Vec<String>,
+}
+
+impl Command for InitIndex {
+    fn run(&self) -> Result<(), Error> {
+        let index_location = self
+            .index_opts
+            .index_location
+            .as_ref()
+            .ok_or(Error::MissingIndexOption("index-location"))?;
+
+        let api_url = match (&self.index_opts.api_url, self.port) {
+            (Some(api_url), _) => api_url.to_owned(),
+            (None, Some(port)) => format!("http://localhost:{}", port),
+            (None, None) => return Err(Error::MissingIndexOption("api-url")),
+        };
+
+        if Repository::create_local_remote(index_location, &self.index_opts.git_opts)? {
+            println!("Created a bare repository at {}", index_location);
+        }
+
+        let checkout_path = empty_checkout_path(&self.index_opts)?;
+        let repo = Repository::init(index_location, &checkout_path, &self.index_opts.git_opts)?;
+
+        let config_path = checkout_path.join("config.json");
+        let config = index::config_json(&api_url, &self.allowed_registries)?;
+
+        if !repo.checkout_remote_branch()? {
+            std::fs::write(&config_path, &config)?;
+            repo.commit_all_and_force_push("Creating the index")?;
+            println!("Created the index in {}", index_location);
+            return Ok(());
+        }
+
+        // An existing config.json is never overwritten, only checked
+        let existing = match std::fs::read(&config_path) {
+            Ok(existing) => existing,
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => {
+                std::fs::write(&config_path, &config)?;
+                repo.commit_and_push("Adding config.json", &[Path::new("config.json")])?;
+                println!("Added config.json to the index in {}", index_location);
+                return Ok(());
+            }
+            Err(err) => return Err(Error::IO(err)),
+        };
+
+        let problems = crate::config::validate(&existing, &api_url, &self.allowed_registries);
+        for problem in &problems {
+            println!("{}", problem);
+        }
+
+        if !problems.is_empty() {
+            return Err(Error::CheckFailed("init-index"));
+        }
+
+        println!("config.json matches {}", api_url);
+
+        Ok(())
+    }
+}
+
+#[derive(StructOpt)]
+pub struct SquashIndex {
+    #[structopt(flatten)]
+    pub index_opts: IndexOpts,
+}
+
+impl Command for SquashIndex {
+    fn run(&self) -> Result<(), Error> {
+        // A hosted index is only changed by the server, which has to hold
+        // the index lock while squashing
+        if self.index_opts.index_mode == IndexMode::Hosted {
+            return Err(Error::SquashRequiresServer);
+        }
+
+        // Publishes through a running server aren't locked out, but fail the
+        // lease of the force push
+        index::open_repository(&self.index_opts)?.squash()
+    }
+}
+
+#[derive(StructOpt)]
+pub struct Fsck {
+    /// URL of database.
+    #[structopt(long = "db-url", env = "DB_URL")]
+    pub db_url: String,
+    #[structopt(flatten)]
+    pub index_opts: IndexOpts,
+    #[structopt(flatten)]
+    pub storage_opts: StorageOpts,
+    /// Repair the index and database where possible
+    #[structopt(long = "fix")]
+    pub fix: bool,
+    /// Output format, either `text` or `json`
+    #[structopt(long = "format", default_value = "text")]
+    pub format: OutputFormat,
+}
+
+impl Command for Fsck {
+    fn run(&self) -> Result<(), Error> {
+        let pool = crate::make_pool(&self.db_url)?;
+        let conn = pool.get()?;
+
+        // Always the git index, which is only an export in database mode
+        let repo = index::open_repository(&self.index_opts)?;
+
+        let storage = storage::new(&self.storage_opts)?;
+
+        let mut report = crate::fsck::check(
+            &Version::all_with_crate_name(&conn)?,
+            &repo.index_files()?,
+            storage.list()?,
+        );
+
+        if self.fix {
+            crate::fsck::fix(&mut report, &conn, &repo)?;
+        }
+
+        match self.format {
+            OutputFormat::Text => print!("{}", report),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+        }
+
+        if !report.is_ok() {
+            return Err(Error::CheckFailed("fsck"));
+        }
 
         Ok(())
     }
 }
 
-#[cfg(feature = "local")]
+/// Opens the index for commands that only need a database connection in
+/// database index mode.
+fn open_index(opts: &IndexOpts, db_url: Option<&String>) -> Result<Arc<dyn Index>, Error> {
+    let pool = match db_url {
+        Some(db_url) => Some(crate::make_pool(db_url)?),
+        None => None,
+    };
+
+    index::open(opts, pool)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(Error::UnknownOutputFormat(format.to_owned())),
+        }
+    }
+}
+
+#[derive(StructOpt)]
+pub struct IndexOpts {
+    /// Where the index is kept, either `git`, `database` or `hosted`
+    #[structopt(long = "index-mode", env = "INDEX_MODE", default_value = "git")]
+    pub index_mode: IndexMode,
+    /// Index location, e.g. git@github.com:nylar/private-registry.git. In
+    /// database mode the index is also exported here if set
+    #[structopt(long = "index-location", env = "INDEX_LOCATION")]
+    pub index_location: Option<String>,
+    /// Checkout path, reused across restarts if it's already a checkout of
+    /// the index. Defaults to a temporary directory. The full history is
+    /// cloned unless `--index-shallow` is given
+    #[structopt(long = "checkout-path", env = "CHECKOUT_PATH")]
+    pub checkout_path: Option<PathBuf>,
+    /// Public URL of pallet, used for `config.json` in database mode
+    #[structopt(long = "api-url", env = "API_URL")]
+    pub api_url: Option<String>,
+    #[structopt(flatten)]
+    pub git_opts: GitOpts,
+}
+
+impl IndexOpts {
+    /// The URL dependencies use to refer to this registry.
+    pub fn registry_url(&self) -> Option<String> {
+        match self.index_mode {
+            IndexMode::Git => self.index_location.clone(),
+            IndexMode::Database => self
+                .api_url
+                .as_ref()
+                .map(|api_url| format!("sparse+{}/index/", api_url.trim_end_matches('/'))),
+            IndexMode::Hosted => self
+                .api_url
+                .as_ref()
+                .map(|api_url| format!("{}/git/index", api_url.trim_end_matches('/'))),
+        }
+    }
+}
+
+#[derive(StructOpt)]
+pub struct GitOpts {
+    /// Branch of the git index to check out and commit to
+    #[structopt(long = "index-branch", env = "INDEX_BRANCH", default_value = "master")]
+    pub index_branch: String,
+    /// Name of the remote the git index is cloned from and pushed to
+    #[structopt(long = "index-remote", env = "INDEX_REMOTE", default_value = "origin")]
+    pub index_remote: String,
+    /// Refspec to push the git index with, e.g.
+    /// `refs/heads/main:refs/heads/index`. The index is fetched from where
+    /// it's pushed to. Defaults to pushing the branch to the branch of the
+    /// same name
+    #[structopt(long = "index-push-refspec", env = "INDEX_PUSH_REFSPEC")]
+    pub index_push_refspec: Option<String>,
+    /// Prefix of the branch to keep the old history of the git index in when
+    /// it's squashed, e.g. `snapshot-`, which is followed by the date and the
+    /// commit it's at
+    #[structopt(long = "index-squash-archive", env = "INDEX_SQUASH_ARCHIVE")]
+    pub index_squash_archive: Option<String>,
+    /// How many times to retry a push the remote rejected because something
+    /// else pushed to the branch first
+    #[structopt(
+        long = "index-push-retries",
+        env = "INDEX_PUSH_RETRIES",
+        default_value = "3"
+    )]
+    pub index_push_retries: u32,
+    /// Clone and fetch only the latest commit of the git index, with the
+    /// `git` command as libgit2 can't make shallow clones
+    #[structopt(long = "index-shallow")]
+    pub index_shallow: bool,
+    /// Name of the committer of index commits. Defaults to `user.name` from
+    /// git's config, or `pallet`
+    #[structopt(long = "index-committer-name", env = "INDEX_COMMITTER_NAME")]
+    pub index_committer_name: Option<String>,
+    /// Email of the committer of index commits. Defaults to `user.email`
+    /// from git's config, or `pallet@localhost`
+    #[structopt(long = "index-committer-email", env = "INDEX_COMMITTER_EMAIL")]
+    pub index_committer_email: Option<String>,
+    /// Commit message for a publish, in which `{name}`, `{version}` and
+    /// `{login}` of the publisher are replaced
+    #[structopt(
+        long = "index-publish-message",
+        env = "INDEX_PUBLISH_MESSAGE",
+        default_value = "Updating crate `{name}#{version}`"
+    )]
+    pub index_publish_message: String,
+    /// Commit message for a yank, with the same placeholders as
+    /// `--index-publish-message`
+    #[structopt(
+        long = "index-yank-message",
+        env = "INDEX_YANK_MESSAGE",
+        default_value = "Yanking crate `{name}#{version}`"
+    )]
+    pub index_yank_message: String,
+    /// Commit message for an unyank, with the same placeholders as
+    /// `--index-publish-message`
+    #[structopt(
+        long = "index-unyank-message",
+        env = "INDEX_UNYANK_MESSAGE",
+        default_value = "Unyanking crate `{name}#{version}`"
+    )]
+    pub index_unyank_message: String,
+    /// Key to sign index commits with, a GPG key ID or the path of an SSH
+    /// key
+    #[structopt(long = "index-signing-key", env = "INDEX_SIGNING_KEY")]
+    pub index_signing_key: Option<String>,
+    /// Kind of `--index-signing-key`, either `gpg` or `ssh`
+    #[structopt(
+        long = "index-signing-format",
+        env = "INDEX_SIGNING_FORMAT",
+        default_value = "gpg"
+    )]
+    pub index_signing_format: SigningFormat,
+    /// Private SSH key to authenticate to the index remote with, for when
+    /// there's no ssh-agent
+    #[structopt(long = "index-ssh-key", env = "INDEX_SSH_KEY")]
+    pub index_ssh_key: Option<PathBuf>,
+    /// Passphrase of `--index-ssh-key`
+    #[structopt(long = "index-ssh-key-passphrase", env = "INDEX_SSH_KEY_PASSPHRASE")]
+    pub index_ssh_key_passphrase: Option<String>,
+    /// Username to authenticate to the index remote over HTTPS with.
+    /// Defaults to the username in `--index-location`, or `git`
+    #[structopt(long = "index-https-username", env = "INDEX_HTTPS_USERNAME")]
+    pub index_https_username: Option<String>,
+    /// Access token to authenticate to the index remote over HTTPS with
+    #[structopt(long = "index-https-token", env = "INDEX_HTTPS_TOKEN")]
+    pub index_https_token: Option<String>,
+    /// File containing the access token to authenticate to the index remote
+    /// over HTTPS with
+    #[structopt(long = "index-https-token-file", env = "INDEX_HTTPS_TOKEN_FILE")]
+    pub index_https_token_file: Option<PathBuf>,
+}
+
+impl GitOpts {
+    pub fn push_refspec(&self) -> String {
+        match self.index_push_refspec {
+            Some(ref refspec) => refspec.to_owned(),
+            None => format!(
+                "refs/heads/{}:refs/heads/{}",
+                self.index_branch, self.index_branch
+            ),
+        }
+    }
+}
+
+#[derive(StructOpt)]
+pub struct StorageOpts {
+    /// Storage backend to store crates in, either `local` or `s3`
+    #[structopt(long = "storage", env = "STORAGE", default_value = "local")]
+    pub storage: StorageKind,
+    #[structopt(flatten)]
+    pub local_opts: LocalOpts,
+    #[structopt(flatten)]
+    pub s3_opts: S3Opts,
+    #[structopt(flatten)]
+    pub encryption_opts: EncryptionOpts,
+}
+
+#[derive(StructOpt)]
+pub struct EncryptionOpts {
+    /// Hex encoded 256-bit master key to encrypt stored crates with
+    #[structopt(long = "encryption-key", env = "ENCRYPTION_KEY")]
+    pub encryption_key: Option<String>,
+    /// File containing the hex encoded 256-bit master key to encrypt stored
+    /// crates with
+    #[structopt(long = "encryption-key-file", env = "ENCRYPTION_KEY_FILE")]
+    pub encryption_key_file: Option<PathBuf>,
+    /// Hex encoded 256-bit master key crates were encrypted with before the
+    /// current one, only used to decrypt them
+    #[structopt(long = "old-encryption-key", env = "OLD_ENCRYPTION_KEY")]
+    pub old_encryption_key: Option<String>,
+    /// File containing the hex encoded 256-bit master key crates were
+    /// encrypted with before the current one
+    #[structopt(long = "old-encryption-key-file", env = "OLD_ENCRYPTION_KEY_FILE")]
+    pub old_encryption_key_file: Option<PathBuf>,
+}
+
+impl EncryptionOpts {
+    pub fn is_enabled(&self) -> bool {
+        self.encryption_key.is_some() || self.encryption_key_file.is_some()
+    }
+}
+
 #[derive(StructOpt)]
 pub struct LocalOpts {
     /// Path to where the crates are stored
     #[structopt(long = "local-base-path", env = "LOCAL_BASE_PATH")]
-    pub local_base_path: PathBuf,
+    pub local_base_path: Option<PathBuf>,
 }
 
-#[cfg(feature = "s3")]
-#[derive(StructOpt)]
+#[derive(Clone, StructOpt)]
 pub struct S3Opts {
     /// S3 region
     #[structopt(long = "s3-region", env = "S3_REGION")]
-    pub s3_region: rusoto_core::Region,
+    pub s3_region: Option<rusoto_core::Region>,
+    /// Endpoint of an S3 compatible service, e.g. http://localhost:9000 for
+    /// MinIO
+    #[structopt(long = "s3-endpoint", env = "S3_ENDPOINT")]
+    pub s3_endpoint: Option<String>,
     /// S3 bucket
     #[structopt(long = "s3-bucket", env = "S3_BUCKET")]
-    pub s3_bucket: String,
-    /// S3 access key
+    pub s3_bucket: Option<String>,
+    /// Prefix prepended to the key of every object stored in the bucket
+    #[structopt(long = "s3-key-prefix", env = "S3_KEY_PREFIX")]
+    pub s3_key_prefix: Option<String>,
+    /// S3 access key, the default credential chain is used if omitted
     #[structopt(long = "s3-access-key", env = "S3_ACCESS_KEY")]
-    pub s3_access_key: String,
-    /// S3 secret key
+    pub s3_access_key: Option<String>,
+    /// S3 secret key, the default credential chain is used if omitted
     #[structopt(long = "s3-secret-key", env = "S3_SECRET_KEY")]
-    pub s3_secret_key: String,
+    pub s3_secret_key: Option<String>,
 }
@@ -44,6 +44,44 @@ impl Config {
     }
 }
 
+/// Checks an existing `config.json` against the registry pallet serves from
+/// `api_url`, returning what doesn't match. Downloads go through pallet in
+/// every download mode, as redirects to storage are made from there.
+pub fn validate(content: &[u8], api_url: &str, allowed_registries: &[String]) -> Vec<String> {
+    let config = match serde_json::from_slice::<Config>(content) {
+        Ok(config) => config,
+        Err(err) => return vec![format!("config.json isn't valid: {}", err)],
+    };
+
+    let api_url = api_url.trim_end_matches('/');
+    let mut problems = Vec::new();
+
+    match config.api {
+        Some(ref api) if api.trim_end_matches('/') == api_url => {}
+        Some(ref api) => problems.push(format!("api is {}, expected {}", api, api_url)),
+        None => problems.push(format!("api is missing, expected {}", api_url)),
+    }
+
+    // Cargo appends `/{crate}/{version}/download` unless there are markers
+    let dl = format!("{}/api/v1/crates", api_url);
+    let with_markers = format!("{}/{{crate}}/{{version}}/download", dl);
+    if config.dl.trim_end_matches('/') != dl && config.dl != with_markers {
+        problems.push(format!("dl is {}, expected {}", config.dl, dl));
+    }
+
+    for registry in allowed_registries {
+        let allowed = config
+            .allowed_registries
+            .as_ref()
+            .map_or(false, |allowed| allowed.contains(registry));
+        if !allowed {
+            problems.push(format!("allowed-registries is missing {}", registry));
+        }
+    }
+
+    problems
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -77,4 +115,36 @@ mod tests {
         assert!(config.registry_allowed(REGISTRY));
         assert!(config.registry_allowed(ALT_REGISTRY));
     }
+
+    #[test]
+    fn validate_against_server_settings() {
+        let json = r#"{
+    "dl": "https://crates.example.com/api/v1/crates/{crate}/{version}/download",
+    "api": "https://crates.example.com/",
+    "allowed-registries": ["https://github.com/nylar/pallet-mirror"]
+}"#;
+
+        assert!(validate(
+            json.as_bytes(),
+            "https://crates.example.com",
+            &[ALT_REGISTRY.to_owned()]
+        )
+        .is_empty());
+
+        assert_eq!(
+            validate(
+                json.as_bytes(),
+                "http://localhost:8080",
+                &[REGISTRY.to_owned()]
+            ),
+            [
+                "api is https://crates.example.com/, expected http://localhost:8080",
+                "dl is https://crates.example.com/api/v1/crates/{crate}/{version}/download, \
+                 expected http://localhost:8080/api/v1/crates",
+                "allowed-registries is missing https://github.com/nylar/pallet-index",
+            ]
+        );
+
+        assert_eq!(validate(b"{}", "http://localhost:8080", &[]).len(), 1);
+    }
 }
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::models::version_downloads::VersionDownloads;
+
+use chrono::{NaiveDate, Utc};
+use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+
+type Key = (String, String, NaiveDate);
+
+/// Download counts that haven't been written to the database yet.
+///
+/// Downloads are counted in memory and flushed in batches, so serving a
+/// download never waits on the database.
+#[derive(Debug, Default)]
+pub struct Downloads {
+    pending: Mutex<HashMap<Key, i32>>,
+}
+
+impl Downloads {
+    pub fn record(&self, name: &str, vers: &str) {
+        // The name is as typed in the download URL, in any case
+        let key = (
+            name.to_lowercase(),
+            vers.to_owned(),
+            Utc::today().naive_utc(),
+        );
+        *self.pending.lock().unwrap().entry(key).or_insert(0) += 1;
+    }
+
+    fn take(&self) -> HashMap<Key, i32> {
+        std::mem::replace(&mut *self.pending.lock().unwrap(), HashMap::new())
+    }
+
+    /// Puts back counts that couldn't be written so they're retried on the
+    /// next flush.
+    fn restore(&self, counts: HashMap<Key, i32>) {
+        let mut pending = self.pending.lock().unwrap();
+        for (key, count) in counts {
+            *pending.entry(key).or_insert(0) += count;
+        }
+    }
+
+    /// Writes every pending count to the database.
+    pub fn flush(&self, conn: &PgConnection) -> Result<(), Error> {
+        let mut counts = self.take();
+
+        let keys = counts.keys().cloned().collect::<Vec<_>>();
+        for key in keys {
+            let (ref name, ref vers, date) = key;
+            if let Err(err) = VersionDownloads::increment(conn, name, vers, date, counts[&key]) {
+                self.restore(counts);
+                return Err(err);
+            }
+            counts.remove(&key);
+        }
+
+        Ok(())
+    }
+}
+
+/// Flushes the pending download counts every `interval` in a background
+/// thread.
+pub fn spawn_flusher(
+    downloads: Arc<Downloads>,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    interval: Duration,
+) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+
+        let result = pool
+            .get()
+            .map_err(Error::Pool)
+            .and_then(|conn| downloads.flush(&conn));
+
+        if let Err(err) = result {
+            warn!("Failed to flush download counts: {}", err);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_and_restore_counts() {
+        let downloads = Downloads::default();
+        downloads.record("foo", "1.0.0");
+        downloads.record("Foo", "1.0.0");
+        downloads.record("bar", "1.0.0");
+
+        let today = Utc::today().naive_utc();
+        let counts = downloads.take();
+
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[&("foo".to_owned(), "1.0.0".to_owned(), today)], 2);
+        assert!(downloads.take().is_empty());
+
+        downloads.record("foo", "1.0.0");
+        downloads.restore(counts);
+
+        let counts = downloads.take();
+        assert_eq!(counts[&("foo".to_owned(), "1.0.0".to_owned(), today)], 3);
+        assert_eq!(counts[&("bar".to_owned(), "1.0.0".to_owned(), today)], 1);
+    }
+}
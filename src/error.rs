@@ -8,12 +8,42 @@ pub enum Error {
     Pool(r2d2::Error),
     Git(git2::Error),
     InvalidRef(String),
+    NonFastForward(String),
     Unauthorized,
     MissingOwners,
-    #[cfg(feature = "s3")]
     UploadS3(rusoto_core::RusotoError<rusoto_s3::PutObjectError>),
+    DownloadS3(rusoto_core::RusotoError<rusoto_s3::GetObjectError>),
+    DeleteS3(rusoto_core::RusotoError<rusoto_s3::DeleteObjectError>),
+    HeadS3(rusoto_core::RusotoError<rusoto_s3::HeadObjectError>),
+    ListS3(rusoto_core::RusotoError<rusoto_s3::ListObjectsV2Error>),
+    ObjectNotFound(String),
+    MissingContentLength(String),
+    ObjectExists(String),
+    ChecksumMismatch(String, String),
+    Credentials(rusoto_credential::CredentialsError),
+    UnknownStorage(String),
+    UnknownDownloadMode(String),
+    UnknownStorageLocation(String),
+    UnknownOutputFormat(String),
+    MissingStorageOption(&'static str),
+    UnknownIndexMode(String),
+    MissingIndexOption(&'static str),
+    IndexWrite(String),
+    IndexWriterStopped,
+    GitProtocol(String),
+    InvalidCheckout(String),
+    UnknownSigningFormat(String),
+    Signing(String),
+    Encryption,
+    Decryption,
+    InvalidEncryptionKey(String),
+    UnknownEncryptionKey(String),
+    EncryptionRequiresProxy,
+    CacheRequiresProxy,
     DisallowedRegistry(String, String),
     UnableToOrphanCrate,
+    CheckFailed(&'static str),
+    SquashRequiresServer,
 }
 
 impl fmt::Display for Error {
@@ -25,14 +55,86 @@ impl fmt::Display for Error {
             Error::Pool(ref err) => err.fmt(f),
             Error::Git(ref err) => err.fmt(f),
             Error::InvalidRef(ref status) => write!(f, "failed to push a ref: {}", status),
+            Error::NonFastForward(ref status) => write!(
+                f,
+                "failed to push a ref, the remote has changed: {}",
+                status
+            ),
             Error::Unauthorized => write!(f, "Unauthorized"),
             Error::MissingOwners => write!(f, "No owners provided"),
-            #[cfg(feature = "s3")]
             Error::UploadS3(ref err) => err.fmt(f),
+            Error::DownloadS3(ref err) => err.fmt(f),
+            Error::DeleteS3(ref err) => err.fmt(f),
+            Error::HeadS3(ref err) => err.fmt(f),
+            Error::ListS3(ref err) => err.fmt(f),
+            Error::Credentials(ref err) => err.fmt(f),
+            Error::ObjectExists(ref key) => write!(
+                f,
+                "Object {} already exists in storage with different content",
+                key
+            ),
+            Error::ObjectNotFound(ref key) => write!(f, "Object {} not found in storage", key),
+            Error::MissingContentLength(ref key) => {
+                write!(f, "Storage didn't say how long object {} is", key)
+            }
+            Error::ChecksumMismatch(ref krate, ref version) => write!(
+                f,
+                "Checksum of {}#{} doesn't match the index",
+                krate, version
+            ),
+            Error::UnknownStorage(ref kind) => write!(f, "Unknown storage backend {}", kind),
+            Error::UnknownDownloadMode(ref mode) => write!(f, "Unknown download mode {}", mode),
+            Error::UnknownStorageLocation(ref location) => write!(
+                f,
+                "Unknown storage location {}, expected local:PATH or s3:BUCKET[/PREFIX]",
+                location
+            ),
+            Error::UnknownOutputFormat(ref format) => write!(f, "Unknown output format {}", format),
+            Error::MissingStorageOption(ref option) => {
+                write!(f, "The selected storage backend requires --{}", option)
+            }
+            Error::UnknownIndexMode(ref mode) => write!(f, "Unknown index mode {}", mode),
+            Error::MissingIndexOption(ref option) => {
+                write!(f, "The selected index mode requires --{}", option)
+            }
+            Error::IndexWrite(ref err) => write!(f, "Failed to write to the index: {}", err),
+            Error::IndexWriterStopped => write!(f, "The index writer has stopped"),
+            Error::GitProtocol(ref reason) => write!(f, "Invalid git request: {}", reason),
+            Error::InvalidCheckout(ref reason) => write!(f, "Invalid index checkout: {}", reason),
+            Error::UnknownSigningFormat(ref format) => {
+                write!(f, "Unknown signing format {}, expected gpg or ssh", format)
+            }
+            Error::Signing(ref reason) => write!(f, "Failed to sign the index commit: {}", reason),
+            Error::Encryption => write!(f, "Failed to encrypt a tarball"),
+            Error::Decryption => write!(
+                f,
+                "Failed to decrypt a tarball, it's corrupted or was encrypted with another key"
+            ),
+            Error::InvalidEncryptionKey(ref reason) => write!(
+                f,
+                "Invalid encryption key, expected 64 hex characters: {}",
+                reason
+            ),
+            Error::UnknownEncryptionKey(ref id) => {
+                write!(f, "Tarball was encrypted with an unknown key {}", id)
+            }
+            Error::EncryptionRequiresProxy => write!(
+                f,
+                "Encrypted storage can't be downloaded from directly, use --download-mode proxy"
+            ),
+            Error::CacheRequiresProxy => write!(
+                f,
+                "The download cache is only used with --download-mode proxy"
+            ),
             Error::DisallowedRegistry(ref krate, ref registry) => {
                 write!(f, "Crate {}'s registry {} is not allowed", krate, registry)
             }
             Error::UnableToOrphanCrate => write!(f, "Can't make a crate an orphan"),
+            Error::CheckFailed(ref command) => write!(f, "{} found problems", command),
+            Error::SquashRequiresServer => write!(
+                f,
+                "A hosted index can only be squashed through the server, with POST /api/v1/admin/index/squash"
+            ),
         }
     }
 }
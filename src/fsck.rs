@@ -0,0 +1,417 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+use crate::index::relative_index_file;
+use crate::metadata::Metadata;
+use crate::models::krate::Krate;
+use crate::models::version::{NewVersion, Version};
+use crate::repository::Repository;
+
+use diesel::pg::PgConnection;
+use serde::Serialize;
+
+/// A way the database, the git index and storage disagree.
+#[derive(Debug, Serialize)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum Problem {
+    /// A version missing from at least one place, with where it was found.
+    Missing {
+        name: String,
+        vers: String,
+        database: bool,
+        index: bool,
+        storage: bool,
+    },
+    YankedMismatch {
+        name: String,
+        vers: String,
+        database: bool,
+        index: bool,
+    },
+    MalformedLine {
+        path: PathBuf,
+        line: usize,
+        error: String,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::Missing {
+                ref name,
+                ref vers,
+                database,
+                index,
+                storage,
+            } => {
+                let missing = [
+                    (database, "the database"),
+                    (index, "the index"),
+                    (storage, "storage"),
+                ]
+                .iter()
+                .filter(|(found, _)| !found)
+                .map(|(_, place)| *place)
+                .collect::<Vec<_>>();
+                write!(f, "{} {}: missing from {}", name, vers, missing.join(", "))
+            }
+            Problem::YankedMismatch {
+                ref name,
+                ref vers,
+                database,
+                index,
+            } => write!(
+                f,
+                "{} {}: yanked is {} in the database but {} in the index",
+                name, vers, database, index
+            ),
+            Problem::MalformedLine {
+                ref path,
+                line,
+                ref error,
+            } => write!(f, "{}:{}: {}", path.display(), line, error),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub versions: usize,
+    pub problems: Vec<Problem>,
+    /// How many of the problems were repaired.
+    pub fixed: usize,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.len() == self.fixed
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        writeln!(
+            f,
+            "{} versions checked, {} problems, {} fixed",
+            self.versions,
+            self.problems.len(),
+            self.fixed
+        )
+    }
+}
+
+type Entries = HashMap<(String, String), (PathBuf, Metadata)>;
+
+/// Parses the index files, reporting lines that aren't valid entries or
+/// aren't in the file of their crate.
+fn parse(files: &[(PathBuf, String)], problems: &mut Vec<Problem>) -> Entries {
+    let mut entries = Entries::new();
+
+    for (path, content) in files {
+        for (line, entry) in content.lines().enumerate() {
+            if entry.trim().is_empty() {
+                continue;
+            }
+
+            let malformed = |error: String| Problem::MalformedLine {
+                path: path.to_owned(),
+                line: line + 1,
+                error,
+            };
+
+            let metadata = match serde_json::from_str::<Metadata>(entry) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    problems.push(malformed(err.to_string()));
+                    continue;
+                }
+            };
+
+            let expected = relative_index_file(&metadata.name);
+            if &expected != path {
+                problems.push(malformed(format!(
+                    "entry for {} belongs in {}",
+                    metadata.name,
+                    expected.display()
+                )));
+                continue;
+            }
+
+            let key = (metadata.name.to_string(), metadata.vers.to_string());
+            if entries.contains_key(&key) {
+                problems.push(malformed(format!(
+                    "duplicate entry for {} {}",
+                    key.0, key.1
+                )));
+                continue;
+            }
+
+            entries.insert(key, (path.to_owned(), metadata));
+        }
+    }
+
+    entries
+}
+
+/// Compares the version rows, the index files and the tarballs in storage.
+pub fn check(
+    rows: &[(String, Version)],
+    files: &[(PathBuf, String)],
+    stored: Vec<(String, String)>,
+) -> Report {
+    let mut report = Report::default();
+
+    let index = parse(files, &mut report.problems);
+
+    let database = rows
+        .iter()
+        .map(|(name, version)| ((name.to_owned(), version.vers.to_owned()), version))
+        .collect::<HashMap<_, _>>();
+
+    let stored = stored.into_iter().collect::<HashSet<_>>();
+
+    let versions = database
+        .keys()
+        .chain(index.keys())
+        .chain(stored.iter())
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    report.versions = versions.len();
+
+    for key in versions {
+        let row = database.get(&key);
+        let entry = index.get(&key);
+        let in_storage = stored.contains(&key);
+        let (name, vers) = key;
+
+        if let (Some(version), Some((_, metadata))) = (row, entry) {
+            if version.yanked != metadata.yanked {
+                report.problems.push(Problem::YankedMismatch {
+                    name: name.to_owned(),
+                    vers: vers.to_owned(),
+                    database: version.yanked,
+                    index: metadata.yanked,
+                });
+            }
+        }
+
+        if row.is_none() || entry.is_none() || !in_storage {
+            report.problems.push(Problem::Missing {
+                name,
+                vers,
+                database: row.is_some(),
+                index: entry.is_some(),
+                storage: in_storage,
+            });
+        }
+    }
+
+    report
+}
+
+/// Repairs what can be repaired, taking the database as the source of truth
+/// for index entries and yanked flags. Versions only in the index get their
+/// row restored if their crate still exists. Missing tarballs are left for
+/// `verify-storage --repair-from`, and versions only in storage for `gc`.
+pub fn fix(report: &mut Report, conn: &PgConnection, repo: &Repository) -> Result<(), Error> {
+    let index = parse(&repo.index_files()?, &mut Vec::new());
+
+    // Rows from before entries were kept in the database would otherwise be
+    // dropped from the regenerated files.
+    let entries = index
+        .values()
+        .map(|(_, metadata)| metadata.clone())
+        .collect::<Vec<_>>();
+    crate::index::import_metadata(conn, &entries)?;
+
+    let mut stale = Vec::new();
+    for (i, problem) in report.problems.iter().enumerate() {
+        match *problem {
+            Problem::Missing {
+                ref name,
+                ref vers,
+                database: false,
+                index: true,
+                storage: true,
+            } => {
+                // The index may have changed since the report was made
+                let metadata = match index.get(&(name.to_owned(), vers.to_owned())) {
+                    Some((_, metadata)) => metadata,
+                    None => continue,
+                };
+                if restore_row(conn, name, metadata)? {
+                    report.fixed += 1;
+                }
+            }
+            Problem::Missing {
+                ref name,
+                database: true,
+                index: false,
+                storage: true,
+                ..
+            }
+            | Problem::YankedMismatch { ref name, .. } => {
+                stale.push((i, relative_index_file(name)));
+            }
+            Problem::MalformedLine { ref path, .. } => stale.push((i, path.to_owned())),
+            _ => {}
+        }
+    }
+
+    let rows = Version::all_with_crate_name(conn)?;
+    let paths = stale
+        .iter()
+        .map(|(_, path)| path.as_path())
+        .collect::<BTreeSet<_>>();
+
+    let mut regenerated = HashSet::new();
+    for path in paths {
+        let rows = rows
+            .iter()
+            .filter(|(name, _)| relative_index_file(name) == path)
+            .collect::<Vec<_>>();
+
+        // Regenerating would drop entries the database doesn't know about
+        let recorded = rows
+            .iter()
+            .filter(|(_, version)| version.metadata.is_some())
+            .map(|(name, version)| (name.to_owned(), version.vers.to_owned()))
+            .collect::<HashSet<_>>();
+        let unrecorded = index
+            .iter()
+            .any(|(key, (file, _))| file == path && !recorded.contains(key));
+        if unrecorded {
+            warn!(
+                "Not regenerating {}, it has entries without a version row",
+                path.display()
+            );
+            continue;
+        }
+
+        let rebuild = crate::rebuild::plan(rows)?;
+        let content = match rebuild.files.get(path) {
+            Some(content) => content,
+            None => continue,
+        };
+
+        fs::write(repo.checkout_path().join(path), content)?;
+        regenerated.insert(path);
+    }
+
+    if !regenerated.is_empty() {
+        let modified = regenerated.iter().cloned().collect::<Vec<&Path>>();
+        repo.commit_and_push(
+            &format!("Repairing {} index files", modified.len()),
+            &modified,
+        )?;
+
+        report.fixed += stale
+            .iter()
+            .filter(|(i, path)| {
+                regenerated.contains(path.as_path()) && is_recorded(&report.problems[*i], &rows)
+            })
+            .count();
+    }
+
+    Ok(())
+}
+
+/// Whether the version a problem is about will be in a regenerated file,
+/// which isn't the case for rows without a recorded entry.
+fn is_recorded(problem: &Problem, rows: &[(String, Version)]) -> bool {
+    match *problem {
+        Problem::Missing {
+            ref name, ref vers, ..
+        } => rows
+            .iter()
+            .any(|(n, version)| n == name && &version.vers == vers && version.metadata.is_some()),
+        _ => true,
+    }
+}
+
+/// Restores the row of a version that's only in the index, if its crate
+/// still exists.
+fn restore_row(conn: &PgConnection, name: &str, metadata: &Metadata) -> Result<bool, Error> {
+    let krate = match Krate::by_name(conn, name)? {
+        Some(krate) => krate,
+        None => return Ok(false),
+    };
+
+    let version = NewVersion {
+        krate_id: krate.id,
+        vers: &metadata.vers.to_string(),
+        yanked: metadata.yanked,
+        cksum: &metadata.cksum,
+    }
+    .save(conn)?;
+    version.set_metadata(conn, &serde_json::to_string(metadata)?)?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::version::row;
+
+    fn line(name: &str, vers: &str, yanked: bool) -> String {
+        format!(
+            r#"{{"name":"{}","vers":"{}","deps":[],"cksum":"abc","features":{{}},"yanked":{},"links":null}}"#,
+            name, vers, yanked
+        )
+    }
+
+    #[test]
+    fn find_drift_between_database_index_and_storage() {
+        let rows = vec![
+            row(1, "foo", "0.1.0", false, false),
+            row(2, "foo", "0.2.0", true, false),
+            row(3, "foo", "0.3.0", false, false),
+        ];
+        let files = vec![(
+            PathBuf::from("3/f/foo"),
+            [
+                line("foo", "0.1.0", false),
+                line("foo", "0.2.0", false),
+                line("foo", "0.4.0", false),
+                line("bar", "0.1.0", false),
+                "{not json".to_owned(),
+            ]
+            .join("\n"),
+        )];
+        let stored = vec![
+            ("foo".to_owned(), "0.1.0".to_owned()),
+            ("foo".to_owned(), "0.2.0".to_owned()),
+            ("foo".to_owned(), "0.4.0".to_owned()),
+            ("baz".to_owned(), "1.0.0".to_owned()),
+        ];
+
+        let report = check(&rows, &files, stored);
+        let problems = report
+            .problems
+            .iter()
+            .map(|problem| problem.to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(report.versions, 5);
+        assert_eq!(problems[0], "3/f/foo:4: entry for bar belongs in 3/b/bar");
+        assert!(problems[1].starts_with("3/f/foo:5: "));
+        assert_eq!(
+            problems[2..],
+            [
+                "baz 1.0.0: missing from the database, the index",
+                "foo 0.2.0: yanked is true in the database but false in the index",
+                "foo 0.3.0: missing from the index, storage",
+                "foo 0.4.0: missing from the database",
+            ]
+        );
+        assert!(!report.is_ok());
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::error::Error;
+use crate::metadata::Metadata;
+use crate::models::version::Version;
+use crate::storage::Storage;
+
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+
+/// What's left behind by publishes that failed part-way, i.e. version rows
+/// and tarballs without an entry in the index.
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// Version rows with the name of their crate.
+    pub rows: Vec<(String, Version)>,
+    /// Name and version of tarballs in storage.
+    pub objects: Vec<(String, String)>,
+    /// Orphans that are still within the grace period, and may belong to a
+    /// publish that's in progress.
+    pub recent: usize,
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty() && self.objects.is_empty()
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, version) in &self.rows {
+            writeln!(f, "orphaned version row: {} {}", name, version.vers)?;
+        }
+        for (name, vers) in &self.objects {
+            writeln!(f, "orphaned tarball: {} {}", name, vers)?;
+        }
+        writeln!(
+            f,
+            "{} orphaned version rows, {} orphaned tarballs, {} within the grace period",
+            self.rows.len(),
+            self.objects.len(),
+            self.recent
+        )
+    }
+}
+
+/// Reconciles the version rows and stored tarballs against the index,
+/// collecting anything older than `cutoff` that has no index entry.
+pub fn plan(
+    entries: &[Metadata],
+    rows: Vec<(String, Version)>,
+    storage: &dyn Storage,
+    cutoff: DateTime<Utc>,
+) -> Result<Plan, Error> {
+    let cutoff = cutoff.naive_utc();
+
+    let indexed = entries
+        .iter()
+        .map(|metadata| (metadata.name.to_string(), metadata.vers.to_string()))
+        .collect::<HashSet<_>>();
+
+    let mut plan = Plan::default();
+
+    for (name, version) in rows {
+        if indexed.contains(&(name.to_owned(), version.vers.to_owned())) {
+            continue;
+        }
+
+        if version.created_at < cutoff {
+            plan.rows.push((name, version));
+        } else {
+            plan.recent += 1;
+        }
+    }
+
+    for (name, vers) in storage.list()? {
+        if indexed.contains(&(name.to_owned(), vers.to_owned())) {
+            continue;
+        }
+
+        // Tarballs without a known age are left alone, they might be in the
+        // middle of being uploaded.
+        match storage.last_modified(&name, &vers)? {
+            Some(last_modified) if last_modified.naive_utc() < cutoff => {
+                plan.objects.push((name, vers))
+            }
+            _ => plan.recent += 1,
+        }
+    }
+
+    plan.rows
+        .sort_by(|a, b| (&a.0, &a.1.vers).cmp(&(&b.0, &b.1.vers)));
+    plan.objects.sort();
+
+    Ok(plan)
+}
+
+/// Deletes everything collected in a plan.
+pub fn collect(plan: &Plan, conn: &PgConnection, storage: &dyn Storage) -> Result<(), Error> {
+    for (name, vers) in &plan.objects {
+        info!("Deleting tarball {} {}", name, vers);
+        storage.delete(name, vers)?;
+    }
+
+    for (name, version) in &plan.rows {
+        info!("Deleting version row {} {}", name, version.vers);
+        version.delete(conn)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::entry;
+    use crate::storage::memory::Memory;
+
+    use chrono::Duration;
+
+    fn version(id: i32, vers: &str, created_at: DateTime<Utc>) -> Version {
+        Version {
+            id,
+            krate_id: 1,
+            vers: vers.to_owned(),
+            yanked: false,
+            cksum: None,
+            created_at: created_at.naive_utc(),
+            metadata: None,
+            updated_at: created_at.naive_utc(),
+            missing_from_index: false,
+        }
+    }
+
+    #[test]
+    fn plan_collects_old_orphans() {
+        let now = Utc::now();
+        let old = now - Duration::days(2);
+        let cutoff = now - Duration::days(1);
+
+        let storage = Memory::default();
+        storage.put("foo", "1.0.0", b"foo").unwrap();
+        storage.put("foo", "1.1.0", b"foo").unwrap();
+        storage.set_last_modified("foo", "1.1.0", old);
+        storage.put("foo", "1.2.0", b"foo").unwrap();
+
+        let entries = vec![entry("foo", "1.0.0", b"foo")];
+
+        let rows = vec![
+            ("foo".to_owned(), version(1, "1.0.0", old)),
+            ("foo".to_owned(), version(2, "1.1.0", old)),
+            ("foo".to_owned(), version(3, "1.3.0", old)),
+            ("foo".to_owned(), version(4, "1.2.0", now)),
+        ];
+
+        let plan = plan(&entries, rows, &storage, cutoff).unwrap();
+
+        assert_eq!(
+            plan.rows
+                .iter()
+                .map(|(_, version)| version.id)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert_eq!(plan.objects, vec![("foo".to_owned(), "1.1.0".to_owned())]);
+        assert_eq!(plan.recent, 2);
+    }
+}
@@ -1,5 +1,58 @@
 use git2;
 use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::commands::GitOpts;
+use crate::error::Error;
+
+/// Credentials for the index remote given on the command line, which are
+/// tried before the ssh-agent and git's credential helpers.
+#[derive(Clone, Default)]
+pub struct GitCredentials {
+    pub ssh_key: Option<PathBuf>,
+    pub ssh_key_passphrase: Option<String>,
+    pub https_username: Option<String>,
+    pub https_token: Option<String>,
+}
+
+impl GitCredentials {
+    pub fn from_opts(opts: &GitOpts) -> Result<Self, Error> {
+        let https_token = match (&opts.index_https_token, &opts.index_https_token_file) {
+            (Some(token), _) => Some(token.to_owned()),
+            (None, Some(path)) => Some(fs::read_to_string(path)?.trim().to_owned()),
+            (None, None) => None,
+        };
+
+        Ok(GitCredentials {
+            ssh_key: opts.index_ssh_key.clone(),
+            ssh_key_passphrase: opts.index_ssh_key_passphrase.clone(),
+            https_username: opts.index_https_username.clone(),
+            https_token,
+        })
+    }
+
+    /// A `git` command which authenticates with these credentials, for the
+    /// operations libgit2 can't do. The SSH key is passed to `ssh` as is, so
+    /// one with a passphrase has to be loaded into the ssh-agent instead.
+    pub fn git_command(&self) -> Command {
+        let mut cmd = Command::new("git");
+        if let Some(ref key) = self.ssh_key {
+            cmd.env(
+                "GIT_SSH_COMMAND",
+                format!("ssh -i '{}' -o IdentitiesOnly=yes", key.display()),
+            );
+        }
+        if let Some(ref token) = self.https_token {
+            let username = self.https_username.as_ref().map_or("git", String::as_str);
+            let basic = base64::encode(&format!("{}:{}", username, token));
+            cmd.arg("-c")
+                .arg(format!("http.extraHeader=Authorization: Basic {}", basic));
+        }
+        cmd
+    }
+}
 
 // git authentication logic borrowed from `cargo` crate
 
@@ -13,6 +66,9 @@ use std::env;
 /// The callback is provided `allowed` types of credentials, and we try to do as
 /// much as possible based on that:
 ///
+/// * Use the SSH key or HTTPS token in `credentials` if there is one, as
+///   there's often no ssh-agent or credential helper in a container.
+///
 /// * Prioritize SSH keys from the local ssh agent as they're likely the most
 ///   reliable. The username here is prioritized from the credential
 ///   callback, then from whatever is configured in git itself, and finally
@@ -30,7 +86,12 @@ use std::env;
 /// credentials until we give it a reason to not do so. To ensure we don't
 /// just sit here looping forever we keep track of authentications we've
 /// attempted and we don't try the same ones again.
-pub fn with_authentication<T, F>(url: &str, cfg: &git2::Config, mut f: F) -> Result<T, git2::Error>
+pub fn with_authentication<T, F>(
+    url: &str,
+    cfg: &git2::Config,
+    credentials: &GitCredentials,
+    mut f: F,
+) -> Result<T, git2::Error>
 where
     F: FnMut(&mut git2::Credentials) -> Result<T, git2::Error>,
 {
@@ -42,9 +103,44 @@ where
     let mut ssh_agent_attempts = Vec::new();
     let mut any_attempts = false;
     let mut tried_sshkey = false;
+    let mut tried_sshkey_file = false;
+    let mut tried_token = false;
 
     let mut res = f(&mut |url, username, allowed| {
         any_attempts = true;
+
+        // An SSH key file is used with the username in the URL, or `git`,
+        // rather than trying usernames with the ssh-agent below.
+        if let Some(ref key) = credentials.ssh_key {
+            if allowed.contains(git2::CredentialType::USERNAME) {
+                return git2::Cred::username("git");
+            }
+            if allowed.contains(git2::CredentialType::SSH_KEY) && !tried_sshkey_file {
+                tried_sshkey_file = true;
+                return git2::Cred::ssh_key(
+                    username.unwrap_or("git"),
+                    None,
+                    key,
+                    credentials.ssh_key_passphrase.as_ref().map(String::as_str),
+                );
+            }
+        }
+
+        // Git hosts take access tokens as the password, mostly whatever the
+        // username is.
+        if let Some(ref token) = credentials.https_token {
+            if allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT) && !tried_token {
+                tried_token = true;
+                let username = credentials
+                    .https_username
+                    .as_ref()
+                    .map(String::as_str)
+                    .or(username)
+                    .unwrap_or("git");
+                return git2::Cred::userpass_plaintext(username, token);
+            }
+        }
+
         // libgit2's "USERNAME" authentication actually means that it's just
         // asking us for a username to keep going. This is currently only really
         // used for SSH authentication and isn't really an authentication type.
@@ -179,6 +275,12 @@ where
     // we try to give a more helpful error message about precisely what we
     // tried.
     let mut msg = "failed to authenticate when downloading repository".to_string();
+    if tried_sshkey_file {
+        msg.push_str("\nattempted authentication with --index-ssh-key, but it was rejected");
+    }
+    if tried_token {
+        msg.push_str("\nattempted authentication with --index-https-token, but it was rejected");
+    }
     if ssh_agent_attempts.is_empty() {
         let names = ssh_agent_attempts
             .iter()
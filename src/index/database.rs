@@ -0,0 +1,136 @@
+use std::path::Path;
+
+use crate::error::Error;
+use crate::metadata::Metadata;
+use crate::models::version::Version as VersionRow;
+use crate::types::CrateName;
+
+use super::{Git, Index, IndexFile};
+
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+use semver::Version;
+
+/// An index kept in the database, made up of the entries recorded on version
+/// rows once their tarballs are stored.
+///
+/// Changes are also committed to a git index if one is given to export to.
+/// Failures to export are logged rather than failing the publish, as the
+/// database is the source of truth.
+pub struct Database {
+    pool: Pool<ConnectionManager<PgConnection>>,
+    config: Vec<u8>,
+    started_at: DateTime<Utc>,
+    export: Option<Git>,
+}
+
+impl Database {
+    pub fn new(
+        pool: Pool<ConnectionManager<PgConnection>>,
+        api_url: &str,
+        export: Option<Git>,
+    ) -> Result<Self, Error> {
+        let config = super::config_json(api_url, &[])?;
+
+        let index = Database {
+            pool,
+            config,
+            started_at: Utc::now(),
+            export,
+        };
+
+        // Picks up versions published before the database was the source of
+        // truth.
+        if let Some(ref export) = index.export {
+            super::import_metadata(&*index.pool.get()?, &export.entries()?)?;
+        }
+
+        Ok(index)
+    }
+
+    fn export(&self, export: impl FnOnce(&Git) -> Result<(), Error>) {
+        if let Some(ref git) = self.export {
+            if let Err(err) = export(git) {
+                warn!("Failed to export to the git index: {}", err);
+            }
+        }
+    }
+}
+
+impl Index for Database {
+    fn add(&self, metadata: &Metadata, login: &str) -> Result<(), Error> {
+        // The entry is recorded on the version row by the caller, which is
+        // what adds it to this index.
+        self.export(|git| git.add(metadata, login));
+
+        Ok(())
+    }
+
+    fn yank(
+        &self,
+        name: &CrateName,
+        version: &Version,
+        yanked: bool,
+        login: &str,
+    ) -> Result<(), Error> {
+        // The yanked column of the version row is updated by the caller, and
+        // takes precedence over the recorded entry.
+        self.export(|git| git.yank(name, version, yanked, login));
+
+        Ok(())
+    }
+
+    fn entries(&self) -> Result<Vec<Metadata>, Error> {
+        let conn = self.pool.get()?;
+
+        let mut entries = Vec::new();
+        for version in VersionRow::indexed(&conn)? {
+            entries.extend(version.index_entry()?);
+        }
+
+        Ok(entries)
+    }
+
+    fn file(&self, path: &Path) -> Result<Option<IndexFile>, Error> {
+        if path == Path::new("config.json") {
+            return Ok(Some(IndexFile {
+                content: self.config.clone(),
+                last_modified: self.started_at,
+            }));
+        }
+
+        let name = match super::crate_name(path) {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+
+        let conn = self.pool.get()?;
+
+        let versions = VersionRow::indexed_by_crate_name(&conn, &name)?;
+        let last_modified = match versions.iter().map(|version| version.updated_at).max() {
+            Some(last_modified) => DateTime::from_utc(last_modified, Utc),
+            None => return Ok(None),
+        };
+
+        let mut content = Vec::new();
+        for version in versions {
+            if let Some(metadata) = version.index_entry()? {
+                serde_json::to_writer(&mut content, &metadata)?;
+                content.push(b'\n');
+            }
+        }
+
+        Ok(Some(IndexFile {
+            content,
+            last_modified,
+        }))
+    }
+
+    fn squash(&self) -> Result<(), Error> {
+        match self.export {
+            Some(ref git) => git.squash(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,422 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::commands::GitOpts;
+use crate::error::Error;
+use crate::metadata::Metadata;
+use crate::repository::Repository;
+use crate::types::CrateName;
+
+use super::smart_http::UploadPack;
+use super::{Index, IndexFile};
+
+use chrono::{TimeZone, Utc};
+use semver::Version;
+
+/// A change to the index waiting to be committed, along with the login of
+/// the owner who made it.
+enum Change {
+    Add(Metadata, String),
+    Yank {
+        name: CrateName,
+        version: Version,
+        yanked: bool,
+        login: String,
+    },
+}
+
+impl Change {
+    /// Writes the change to the checkout, returning the index file it
+    /// modified.
+    fn apply(&self, repo: &Repository) -> Result<PathBuf, Error> {
+        match *self {
+            Change::Add(ref metadata, _) => {
+                let dst = repo.index_file(&*metadata.name);
+                fs::create_dir_all(dst.parent().unwrap())?;
+
+                let mut file = OpenOptions::new().append(true).create(true).open(&dst)?;
+                serde_json::to_writer(&mut file, metadata)?;
+                file.write_all(b"\n")?;
+
+                Ok(repo.relative_index_file(&metadata.name))
+            }
+            Change::Yank {
+                ref name,
+                ref version,
+                yanked,
+                ..
+            } => {
+                let dst = repo.index_file(&name);
+
+                let prev = fs::read_to_string(&dst)?;
+                let new = prev
+                    .lines()
+                    .map(|line| {
+                        let mut git_crate = serde_json::from_str::<Metadata>(line)?;
+                        if &git_crate.name != name || git_crate.vers != *version {
+                            return Ok(line.to_string());
+                        }
+                        git_crate.yanked = yanked;
+                        Ok(serde_json::to_string(&git_crate)?)
+                    })
+                    .collect::<Result<Vec<_>, Error>>();
+                let new = new?.join("\n") + "\n";
+                fs::write(&dst, new.as_bytes())?;
+
+                Ok(repo.relative_index_file(name))
+            }
+        }
+    }
+
+    fn message(&self, messages: &Messages) -> String {
+        let (template, name, version, login) = match *self {
+            Change::Add(ref metadata, ref login) => {
+                (&messages.publish, &*metadata.name, &metadata.vers, login)
+            }
+            Change::Yank {
+                ref name,
+                ref version,
+                yanked,
+                ref login,
+            } => (
+                if yanked {
+                    &messages.yank
+                } else {
+                    &messages.unyank
+                },
+                &**name,
+                version,
+                login,
+            ),
+        };
+
+        template
+            .replace("{name}", name)
+            .replace("{version}", &version.to_string())
+            .replace("{login}", login)
+    }
+}
+
+/// Commit message templates, from `--index-publish-message` and friends.
+struct Messages {
+    publish: String,
+    yank: String,
+    unyank: String,
+}
+
+impl Messages {
+    fn new(opts: &GitOpts) -> Self {
+        Messages {
+            publish: opts.index_publish_message.to_owned(),
+            yank: opts.index_yank_message.to_owned(),
+            unyank: opts.index_unyank_message.to_owned(),
+        }
+    }
+}
+
+/// The commit message for a batch of changes, listing each change when
+/// there's more than one.
+fn commit_message(changes: &[&Change], messages: &Messages) -> String {
+    match changes {
+        [change] => change.message(messages),
+        changes => format!(
+            "Updating {} crates\n\n{}",
+            changes.len(),
+            changes
+                .iter()
+                .map(|change| change.message(messages))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ),
+    }
+}
+
+struct Job {
+    change: Change,
+    done: mpsc::Sender<Result<(), Error>>,
+}
+
+/// An index kept in a git repository, which every change is committed and
+/// pushed to.
+///
+/// Changes are queued for a background writer, which commits everything
+/// queued while the previous push was in flight together and pushes once.
+/// Each caller still waits for its own change to be pushed.
+pub struct Git {
+    repository: Arc<Mutex<Repository>>,
+    writer: Mutex<mpsc::Sender<Job>>,
+    /// Another handle on the checkout, to serve index files from without
+    /// waiting for the writer.
+    reader: Mutex<git2::Repository>,
+    /// The last commit known to be pushed, which index files are served from.
+    published: Arc<Mutex<git2::Oid>>,
+    /// Where the repository is, if pallet hosts it itself.
+    hosted: Option<PathBuf>,
+}
+
+impl Git {
+    pub fn new(repository: Repository, opts: &GitOpts) -> Result<Self, Error> {
+        let reader = git2::Repository::open(repository.checkout_path())?;
+        let published = Arc::new(Mutex::new(repository.head_id()?));
+        let hosted = if repository.is_hosted() {
+            Some(repository.checkout_path().to_path_buf())
+        } else {
+            None
+        };
+
+        let repository = Arc::new(Mutex::new(repository));
+        let (writer, jobs) = mpsc::channel();
+
+        let repo = repository.clone();
+        let head = published.clone();
+        let messages = Messages::new(opts);
+        thread::spawn(move || {
+            while let Ok(job) = jobs.recv() {
+                let mut batch = vec![job];
+                batch.extend(jobs.try_iter());
+                write_batch(&repo.lock().unwrap(), &head, &messages, batch);
+            }
+        });
+
+        Ok(Git {
+            repository,
+            writer: Mutex::new(writer),
+            reader: Mutex::new(reader),
+            published,
+            hosted,
+        })
+    }
+
+    /// Opens another handle on the hosted repository to serve fetches from,
+    /// so they don't wait for the writer.
+    fn open_hosted(&self) -> Result<git2::Repository, Error> {
+        let path = self
+            .hosted
+            .as_ref()
+            .ok_or_else(|| Error::GitProtocol("the index isn't hosted by pallet".to_owned()))?;
+        Ok(git2::Repository::open(path)?)
+    }
+
+    /// Advertises the refs of the hosted repository to git clients.
+    pub fn advertise_refs(&self) -> Result<Vec<u8>, Error> {
+        super::smart_http::advertise_refs(&self.open_hosted()?)
+    }
+
+    /// Negotiates a fetch from the hosted repository.
+    pub fn upload_pack(&self, request: &[u8]) -> Result<UploadPack, Error> {
+        super::smart_http::upload_pack(&self.open_hosted()?, request)
+    }
+
+    /// Writes the pack for a negotiated fetch, passing it to `send` as it's
+    /// written.
+    pub fn write_pack(
+        &self,
+        upload: &UploadPack,
+        send: impl FnMut(&[u8]) -> bool,
+    ) -> Result<(), Error> {
+        super::smart_http::write_pack(&self.open_hosted()?, upload, send)
+    }
+
+    /// Queues a change and waits until it's been pushed.
+    fn write(&self, change: Change) -> Result<(), Error> {
+        let (done, result) = mpsc::channel();
+        self.writer
+            .lock()
+            .unwrap()
+            .send(Job { change, done })
+            .map_err(|_| Error::IndexWriterStopped)?;
+
+        result.recv().map_err(|_| Error::IndexWriterStopped)?
+    }
+}
+
+/// Applies a batch of changes on top of the remote branch and pushes them
+/// in one commit. A change that can't be applied fails on its own, but a
+/// failed push fails the whole batch.
+///
+/// If something else pushed to the branch first, the changes are applied
+/// again on top of the new head and pushed after a backoff, up to the
+/// repository's retry limit.
+fn write_batch(
+    repo: &Repository,
+    published: &Mutex<git2::Oid>,
+    messages: &Messages,
+    mut batch: Vec<Job>,
+) {
+    debug!("Writing {} changes to the index", batch.len());
+
+    let mut attempt = 0;
+    loop {
+        if let Err(err) = repo.reset_head() {
+            let err = err.to_string();
+            for job in batch {
+                let _ = job.done.send(Err(Error::IndexWrite(err.clone())));
+            }
+            return;
+        }
+        // Whatever else was pushed is served right away
+        publish(repo, published);
+
+        let mut applied = Vec::new();
+        for job in batch {
+            match job.change.apply(repo) {
+                Ok(path) => applied.push((job, path)),
+                Err(err) => {
+                    let _ = job.done.send(Err(err));
+                }
+            }
+        }
+
+        if applied.is_empty() {
+            return;
+        }
+
+        let changes = applied
+            .iter()
+            .map(|(job, _)| &job.change)
+            .collect::<Vec<_>>();
+        let paths = applied
+            .iter()
+            .map(|(_, path)| path.as_path())
+            .collect::<Vec<_>>();
+
+        let result = match repo.commit_and_push(&commit_message(&changes, messages), &paths) {
+            Err(Error::NonFastForward(ref status)) if attempt < repo.push_retries() => {
+                attempt += 1;
+                let backoff = Duration::from_millis(100 << attempt.min(6));
+                warn!(
+                    "The index push was rejected ({}), retrying in {:?}",
+                    status, backoff
+                );
+                thread::sleep(backoff);
+
+                batch = applied.into_iter().map(|(job, _)| job).collect();
+                continue;
+            }
+            result => result.map_err(|err| err.to_string()),
+        };
+        if result.is_ok() {
+            publish(repo, published);
+        }
+
+        for (job, _) in applied {
+            let _ = job.done.send(result.clone().map_err(Error::IndexWrite));
+        }
+        return;
+    }
+}
+
+/// Serves index files from where the branch is, once it's known to be pushed.
+fn publish(repo: &Repository, published: &Mutex<git2::Oid>) {
+    match repo.head_id() {
+        Ok(head) => *published.lock().unwrap() = head,
+        Err(err) => warn!("Failed to find the head of the index: {}", err),
+    }
+}
+
+impl Index for Git {
+    fn add(&self, metadata: &Metadata, login: &str) -> Result<(), Error> {
+        self.write(Change::Add(metadata.clone(), login.to_owned()))
+    }
+
+    fn yank(
+        &self,
+        name: &CrateName,
+        version: &Version,
+        yanked: bool,
+        login: &str,
+    ) -> Result<(), Error> {
+        self.write(Change::Yank {
+            name: name.clone(),
+            version: version.clone(),
+            yanked,
+            login: login.to_owned(),
+        })
+    }
+
+    fn entries(&self) -> Result<Vec<Metadata>, Error> {
+        let repo = self.repository.lock().unwrap();
+        repo.reset_head()?;
+        publish(&repo, &self.published);
+        repo.entries()
+    }
+
+    fn file(&self, path: &Path) -> Result<Option<IndexFile>, Error> {
+        // Served as often as builds run, so this doesn't fetch from the
+        // remote. It's read from the last pushed commit rather than the
+        // checkout, which may hold a commit whose push is still failing.
+        let published = *self.published.lock().unwrap();
+        let reader = self.reader.lock().unwrap();
+        let commit = reader.find_commit(published)?;
+
+        let entry = match commit.tree()?.get_path(path) {
+            Ok(entry) => entry,
+            Err(ref err) if err.code() == git2::ErrorCode::NotFound => return Ok(None),
+            Err(err) => return Err(Error::Git(err)),
+        };
+        let blob = match entry.to_object(&reader)?.into_blob() {
+            Ok(blob) => blob,
+            Err(_) => return Ok(None),
+        };
+
+        Ok(Some(IndexFile {
+            content: blob.content().to_vec(),
+            last_modified: Utc.timestamp(commit.time().seconds(), 0),
+        }))
+    }
+
+    fn squash(&self) -> Result<(), Error> {
+        // Holding the lock keeps the writer from pushing while squashing
+        let repo = self.repository.lock().unwrap();
+        repo.squash()?;
+        publish(&repo, &self.published);
+        Ok(())
+    }
+
+    fn hosted(&self) -> Option<&Git> {
+        self.hosted.as_ref().map(|_| self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    fn yank(name: &str, version: &str, yanked: bool) -> Change {
+        Change::Yank {
+            name: CrateName::from_str(name).unwrap(),
+            version: Version::parse(version).unwrap(),
+            yanked,
+            login: "alice".to_owned(),
+        }
+    }
+
+    fn messages() -> Messages {
+        Messages {
+            publish: "Updating crate `{name}#{version}`".to_owned(),
+            yank: "Yanking crate `{name}#{version}`".to_owned(),
+            unyank: "{login} unyanked {name} {version}".to_owned(),
+        }
+    }
+
+    #[test]
+    fn commit_message_lists_batched_changes() {
+        let foo = yank("foo", "1.0.0", true);
+        let bar = yank("bar", "0.1.0", false);
+
+        assert_eq!(
+            commit_message(&[&foo], &messages()),
+            "Yanking crate `foo#1.0.0`"
+        );
+        assert_eq!(
+            commit_message(&[&foo, &bar], &messages()),
+            "Updating 2 crates\n\nYanking crate `foo#1.0.0`\nalice unyanked bar 0.1.0"
+        );
+    }
+}
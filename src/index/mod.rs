@@ -0,0 +1,256 @@
+mod database;
+mod git;
+mod smart_http;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::commands::IndexOpts;
+use crate::error::Error;
+use crate::metadata::Metadata;
+use crate::models::version::Version as VersionRow;
+use crate::repository::Repository;
+use crate::types::CrateName;
+
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+use semver::Version;
+
+pub use database::Database;
+pub use git::Git;
+pub use smart_http::UploadPack;
+
+/// Where the entries of published crate versions are kept.
+pub trait Index: Send + Sync {
+    /// Adds the entry for a crate version `login` published.
+    fn add(&self, metadata: &Metadata, login: &str) -> Result<(), Error>;
+
+    /// Marks a crate version as yanked or unyanked on behalf of `login`.
+    fn yank(
+        &self,
+        name: &CrateName,
+        version: &Version,
+        yanked: bool,
+        login: &str,
+    ) -> Result<(), Error>;
+
+    /// Reads every entry in the index.
+    fn entries(&self) -> Result<Vec<Metadata>, Error>;
+
+    /// Reads a file at `path` relative to the root of the index, i.e.
+    /// `config.json` or the index file of a crate.
+    fn file(&self, path: &Path) -> Result<Option<IndexFile>, Error>;
+
+    /// Squashes the history of the git index into a single commit, if
+    /// there's a git index.
+    fn squash(&self) -> Result<(), Error>;
+
+    /// The git index, if pallet hosts it and serves it to git clients.
+    fn hosted(&self) -> Option<&Git> {
+        None
+    }
+}
+
+/// The content of a file in the index.
+pub struct IndexFile {
+    pub content: Vec<u8>,
+    pub last_modified: DateTime<Utc>,
+}
+
+/// Where the index is kept.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndexMode {
+    /// A git repository cloned from `--index-location`.
+    Git,
+    /// The database, served only over the sparse protocol.
+    Database,
+    /// A git repository in `--checkout-path` that pallet serves itself.
+    Hosted,
+}
+
+impl FromStr for IndexMode {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "git" => Ok(IndexMode::Git),
+            "database" => Ok(IndexMode::Database),
+            "hosted" => Ok(IndexMode::Hosted),
+            _ => Err(Error::UnknownIndexMode(mode.to_owned())),
+        }
+    }
+}
+
+/// Opens the index selected by `--index-mode`. The database index needs a
+/// connection pool, and exports to git if `--index-location` is set.
+pub fn open(
+    opts: &IndexOpts,
+    pool: Option<Pool<ConnectionManager<PgConnection>>>,
+) -> Result<Arc<dyn Index>, Error> {
+    match opts.index_mode {
+        IndexMode::Git | IndexMode::Hosted => {
+            Ok(Arc::new(Git::new(open_repository(opts)?, &opts.git_opts)?))
+        }
+        IndexMode::Database => {
+            let pool = pool.ok_or(Error::MissingIndexOption("db-url"))?;
+            let api_url = opts
+                .api_url
+                .as_ref()
+                .ok_or(Error::MissingIndexOption("api-url"))?;
+            let export = match opts.index_location {
+                Some(_) => Some(Git::new(open_repository(opts)?, &opts.git_opts)?),
+                None => None,
+            };
+            Ok(Arc::new(Database::new(pool, api_url, export)?))
+        }
+    }
+}
+
+/// Clones the index into `--checkout-path`, or a temporary directory if it
+/// isn't set. A hosted index is opened in `--checkout-path`, which is
+/// required as it's the only copy.
+pub fn open_repository(opts: &IndexOpts) -> Result<Repository, Error> {
+    if opts.index_mode == IndexMode::Hosted {
+        let checkout_path = opts
+            .checkout_path
+            .as_ref()
+            .ok_or(Error::MissingIndexOption("checkout-path"))?;
+        let api_url = opts
+            .api_url
+            .as_ref()
+            .ok_or(Error::MissingIndexOption("api-url"))?;
+        return Repository::host(checkout_path, &opts.git_opts, &config_json(api_url, &[])?);
+    }
+
+    let index_location = opts
+        .index_location
+        .as_ref()
+        .ok_or(Error::MissingIndexOption("index-location"))?;
+
+    let checkout_path = match opts.checkout_path {
+        Some(ref checkout_path) => checkout_path.to_owned(),
+        None => tempfile::TempDir::new()?.into_path(),
+    };
+
+    Repository::open(index_location, &checkout_path, &opts.git_opts)
+}
+
+/// Squashes the history of the git index every `interval`.
+pub fn spawn_squasher(index: Arc<dyn Index>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+
+        if let Err(err) = index.squash() {
+            warn!("Failed to squash the index history: {}", err);
+        }
+    });
+}
+
+/// Generates `config.json` for a registry served from `api_url`, whose crates
+/// may also depend on crates from `allowed_registries`.
+pub fn config_json(api_url: &str, allowed_registries: &[String]) -> Result<Vec<u8>, Error> {
+    let api_url = api_url.trim_end_matches('/');
+    let mut config = serde_json::json!({
+        "dl": format!("{}/api/v1/crates", api_url),
+        "api": api_url,
+    });
+    if !allowed_registries.is_empty() {
+        config["allowed-registries"] = serde_json::json!(allowed_registries);
+    }
+    Ok(serde_json::to_vec_pretty(&config)?)
+}
+
+/// Records the entries of versions published before entries were kept on
+/// their rows, taking them from an existing index.
+pub fn import_metadata(conn: &PgConnection, entries: &[Metadata]) -> Result<(), Error> {
+    let entries = entries
+        .iter()
+        .map(|metadata| {
+            (
+                (metadata.name.to_string(), metadata.vers.to_string()),
+                metadata,
+            )
+        })
+        .collect::<HashMap<_, _>>();
+
+    for (name, version) in VersionRow::all_with_crate_name(conn)? {
+        if version.metadata.is_some() {
+            continue;
+        }
+
+        if version.missing_from_index {
+            continue;
+        }
+
+        match entries.get(&(name.to_owned(), version.vers.to_owned())) {
+            Some(metadata) => {
+                info!("Importing the index entry of {} {}", name, version.vers);
+                version.set_metadata(conn, &serde_json::to_string(metadata)?)?;
+            }
+            None => {
+                warn!("{} {} isn't in the index, run fsck", name, version.vers);
+                version.set_missing_from_index(conn)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The path of a crate's index file relative to the root of the index.
+pub fn relative_index_file(name: &str) -> PathBuf {
+    let name = name.to_lowercase();
+    match name.len() {
+        1 => Path::new("1").join(&name),
+        2 => Path::new("2").join(&name),
+        3 => Path::new("3").join(&name[..1]).join(&name),
+        _ => Path::new(&name[0..2]).join(&name[2..4]).join(&name),
+    }
+}
+
+/// The name of the crate an index file belongs to, if `path` is where its
+/// index file would be.
+pub fn crate_name(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    // Check the name first, as only ASCII names can be split into a prefix
+    let valid = name.is_ascii() && CrateName::from_str(name).is_ok();
+    if valid && relative_index_file(name) == path {
+        Some(name.to_owned())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_mode_from_string() {
+        assert_eq!(IndexMode::from_str("git").unwrap(), IndexMode::Git);
+        assert_eq!(
+            IndexMode::from_str("database").unwrap(),
+            IndexMode::Database
+        );
+        assert_eq!(IndexMode::from_str("hosted").unwrap(), IndexMode::Hosted);
+        assert!(IndexMode::from_str("svn").is_err());
+    }
+
+    #[test]
+    fn crate_name_from_index_path() {
+        assert_eq!(crate_name(Path::new("1/a")), Some("a".to_owned()));
+        assert_eq!(crate_name(Path::new("3/f/foo")), Some("foo".to_owned()));
+        assert_eq!(
+            crate_name(Path::new("se/rd/serde")),
+            Some("serde".to_owned())
+        );
+        assert_eq!(crate_name(Path::new("se/rd/foo")), None);
+        assert_eq!(crate_name(Path::new("2/..")), None);
+        assert_eq!(crate_name(Path::new("config.json")), None);
+    }
+}
@@ -0,0 +1,350 @@
+//! The server side of fetching over git's smart HTTP protocol, version 0.
+//!
+//! Only what cargo and git need to clone and fetch is supported: no shallow
+//! clones, side-band or multi-ack, and no pushes.
+
+use std::collections::HashSet;
+use std::str;
+
+use crate::error::Error;
+
+use git2::{ObjectType, Oid, PackBuilder, Repository, Tree};
+
+const FLUSH: &[u8] = b"0000";
+
+/// Appends `data` as a pkt-line, i.e. prefixed with its length as four hex
+/// digits.
+fn pkt_line(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(format!("{:04x}", data.len() + 4).as_bytes());
+    out.extend_from_slice(data);
+}
+
+/// Splits a request into its pkt-lines, leaving out flush packets.
+fn pkt_lines(mut data: &[u8]) -> Result<Vec<&[u8]>, Error> {
+    let mut lines = Vec::new();
+
+    while !data.is_empty() {
+        let len = data
+            .get(..4)
+            .and_then(|len| str::from_utf8(len).ok())
+            .and_then(|len| usize::from_str_radix(len, 16).ok())
+            .ok_or_else(|| Error::GitProtocol("invalid pkt-line length".to_owned()))?;
+
+        match len {
+            0 => data = &data[4..],
+            len if len >= 4 && len <= data.len() => {
+                lines.push(&data[4..len]);
+                data = &data[len..];
+            }
+            _ => return Err(Error::GitProtocol("truncated pkt-line".to_owned())),
+        }
+    }
+
+    Ok(lines)
+}
+
+/// The tip of every branch, along with `HEAD` first.
+fn refs(repo: &Repository) -> Result<Vec<(String, Oid)>, Error> {
+    let mut refs = Vec::new();
+
+    for reference in repo.references_glob("refs/heads/*")? {
+        let reference = reference?;
+        if let (Some(name), Some(target)) = (reference.name(), reference.target()) {
+            refs.push((name.to_owned(), target));
+        }
+    }
+    refs.sort();
+
+    // HEAD has no target until the first commit
+    if let Some(target) = repo.head().ok().and_then(|head| head.target()) {
+        refs.insert(0, ("HEAD".to_owned(), target));
+    }
+
+    Ok(refs)
+}
+
+/// The response to `GET info/refs?service=git-upload-pack`, advertising the
+/// refs that can be fetched.
+pub fn advertise_refs(repo: &Repository) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::new();
+    pkt_line(&mut out, b"# service=git-upload-pack\n");
+    out.extend_from_slice(FLUSH);
+
+    let head = repo.find_reference("HEAD")?;
+    let capabilities = match head.symbolic_target() {
+        Some(target) => format!("symref=HEAD:{} agent=pallet", target),
+        None => "agent=pallet".to_owned(),
+    };
+
+    let refs = refs(repo)?;
+    if refs.is_empty() {
+        let line = format!("{} capabilities^{{}}\0{}\n", Oid::zero(), capabilities);
+        pkt_line(&mut out, line.as_bytes());
+    }
+
+    for (i, (name, target)) in refs.iter().enumerate() {
+        let line = if i == 0 {
+            format!("{} {}\0{}\n", target, name, capabilities)
+        } else {
+            format!("{} {}\n", target, name)
+        };
+        pkt_line(&mut out, line.as_bytes());
+    }
+
+    out.extend_from_slice(FLUSH);
+
+    Ok(out)
+}
+
+/// The response to `POST git-upload-pack`, negotiated up front so that the
+/// pack can be streamed by `write_pack` separately.
+pub struct UploadPack {
+    /// Acknowledges the first have in common, if any.
+    pub ack: Vec<u8>,
+    wants: Vec<Oid>,
+    common: Vec<Oid>,
+    done: bool,
+}
+
+impl UploadPack {
+    /// Whether the client is done negotiating, so a pack follows the ack.
+    pub fn has_pack(&self) -> bool {
+        self.done
+    }
+}
+
+/// Negotiates the response to `POST git-upload-pack`.
+///
+/// Requests are stateless, so each one repeats the wants and all the haves
+/// so far. Until the client says it's done, the first have in common is
+/// acknowledged so it can stop sending more. Once it's done, the pack of
+/// everything it doesn't have follows.
+pub fn upload_pack(repo: &Repository, request: &[u8]) -> Result<UploadPack, Error> {
+    let mut wants = Vec::new();
+    let mut haves = Vec::new();
+    let mut done = false;
+
+    for line in pkt_lines(request)? {
+        let line = str::from_utf8(line)
+            .map_err(|_| Error::GitProtocol("pkt-line isn't UTF-8".to_owned()))?
+            .trim_end();
+
+        // Capabilities follow the first want, which are all ignored
+        let mut words = line.split(' ');
+        match (words.next(), words.next()) {
+            (Some("want"), Some(oid)) => wants.push(parse_oid(oid)?),
+            (Some("have"), Some(oid)) => haves.push(parse_oid(oid)?),
+            (Some("done"), None) => done = true,
+            _ => return Err(Error::GitProtocol(format!("unsupported line {}", line))),
+        }
+    }
+
+    // Only the advertised refs can be fetched
+    let tips = refs(repo)?
+        .into_iter()
+        .map(|(_, target)| target)
+        .collect::<HashSet<_>>();
+    if let Some(want) = wants.iter().find(|want| !tips.contains(*want)) {
+        return Err(Error::GitProtocol(format!("{} isn't a ref", want)));
+    }
+
+    let common = haves
+        .into_iter()
+        .filter(|have| repo.find_commit(*have).is_ok())
+        .collect::<Vec<_>>();
+
+    let mut ack = Vec::new();
+    match common.first() {
+        Some(oid) => pkt_line(&mut ack, format!("ACK {}\n", oid).as_bytes()),
+        None => pkt_line(&mut ack, b"NAK\n"),
+    }
+
+    Ok(UploadPack {
+        ack,
+        wants,
+        common,
+        done,
+    })
+}
+
+fn parse_oid(oid: &str) -> Result<Oid, Error> {
+    Oid::from_str(oid).map_err(|_| Error::GitProtocol(format!("invalid object id {}", oid)))
+}
+
+/// Packs the commits reachable from the wants but not the common haves, along
+/// with their trees and blobs, leaving out those in the trees of the haves.
+/// The pack is passed to `send` as it's written, which returns false to stop
+/// if the client has gone away.
+pub fn write_pack(
+    repo: &Repository,
+    upload: &UploadPack,
+    send: impl FnMut(&[u8]) -> bool,
+) -> Result<(), Error> {
+    let mut walk = repo.revwalk()?;
+    for want in &upload.wants {
+        walk.push(*want)?;
+    }
+
+    let mut known = HashSet::new();
+    for have in &upload.common {
+        walk.hide(*have)?;
+        mark_tree(repo, &repo.find_commit(*have)?.tree()?, &mut known)?;
+    }
+
+    let mut builder = repo.packbuilder()?;
+    for commit in walk {
+        let commit = repo.find_commit(commit?)?;
+        builder.insert_object(commit.id(), None)?;
+        insert_tree(repo, &mut builder, &commit.tree()?, &mut known)?;
+    }
+
+    builder.foreach(send)?;
+
+    Ok(())
+}
+
+/// Adds a tree and everything in it that isn't `known` to the pack.
+fn insert_tree(
+    repo: &Repository,
+    builder: &mut PackBuilder,
+    tree: &Tree,
+    known: &mut HashSet<Oid>,
+) -> Result<(), Error> {
+    if !known.insert(tree.id()) {
+        return Ok(());
+    }
+    builder.insert_object(tree.id(), None)?;
+
+    for entry in tree.iter() {
+        match entry.kind() {
+            Some(ObjectType::Tree) => {
+                insert_tree(repo, builder, &repo.find_tree(entry.id())?, known)?
+            }
+            Some(ObjectType::Blob) if known.insert(entry.id()) => {
+                builder.insert_object(entry.id(), None)?;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Marks a tree and everything in it as already on the client.
+fn mark_tree(repo: &Repository, tree: &Tree, known: &mut HashSet<Oid>) -> Result<(), Error> {
+    if !known.insert(tree.id()) {
+        return Ok(());
+    }
+
+    for entry in tree.iter() {
+        match entry.kind() {
+            Some(ObjectType::Tree) => mark_tree(repo, &repo.find_tree(entry.id())?, known)?,
+            _ => {
+                known.insert(entry.id());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    fn commit(repo: &Repository, file: &str, parents: &[Oid]) -> Oid {
+        std::fs::write(repo.workdir().unwrap().join(file), file).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(file)).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+
+        let parents = parents
+            .iter()
+            .map(|parent| repo.find_commit(*parent).unwrap())
+            .collect::<Vec<_>>();
+        let parents = parents.iter().collect::<Vec<_>>();
+
+        let sig = git2::Signature::now("pallet", "pallet@localhost").unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, file, &tree, &parents)
+            .unwrap()
+    }
+
+    fn request(lines: &[String]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for line in lines {
+            if line.is_empty() {
+                out.extend_from_slice(FLUSH);
+            } else {
+                pkt_line(&mut out, line.as_bytes());
+            }
+        }
+        out
+    }
+
+    fn upload(repo: &Repository, request: &[u8]) -> Result<Vec<u8>, Error> {
+        let upload = upload_pack(repo, request)?;
+
+        let mut out = upload.ack.clone();
+        if upload.has_pack() {
+            write_pack(repo, &upload, |data| {
+                out.extend_from_slice(data);
+                true
+            })?;
+        }
+
+        Ok(out)
+    }
+
+    #[test]
+    fn clone_and_fetch() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        repo.set_head("refs/heads/master").unwrap();
+
+        let first = commit(&repo, "config.json", &[]);
+
+        let refs = String::from_utf8(advertise_refs(&repo).unwrap()).unwrap();
+        assert!(refs.starts_with("001e# service=git-upload-pack\n0000"));
+        assert!(refs.contains(&format!(
+            "{} HEAD\0symref=HEAD:refs/heads/master agent=pallet\n",
+            first
+        )));
+        assert!(refs.contains(&format!("{} refs/heads/master\n", first)));
+        assert!(refs.ends_with("0000"));
+
+        let clone = upload(
+            &repo,
+            &request(&[
+                format!("want {} agent=git/2\n", first),
+                "".to_owned(),
+                "done\n".to_owned(),
+            ]),
+        )
+        .unwrap();
+        assert!(clone.starts_with(b"0008NAK\nPACK"));
+
+        let second = commit(&repo, "foo", &[first]);
+
+        // Negotiating, then fetching only what's new
+        let fetch = [
+            format!("want {}\n", second),
+            "".to_owned(),
+            format!("have {}\n", first),
+        ];
+        let ack = upload(&repo, &request(&fetch)).unwrap();
+        assert_eq!(ack, format!("0031ACK {}\n", first).as_bytes());
+
+        let mut fetch = fetch.to_vec();
+        fetch.push("done\n".to_owned());
+        let fetched = upload(&repo, &request(&fetch)).unwrap();
+        let pack = &fetched[ack.len()..];
+        assert!(pack.starts_with(b"PACK"));
+        // A commit, a tree and a blob
+        assert_eq!(&pack[8..12], &[0u8, 0, 0, 3]);
+
+        assert!(upload(&repo, &request(&[format!("want {}\n", Oid::zero())])).is_err());
+    }
+}
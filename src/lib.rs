@@ -8,40 +8,55 @@ extern crate diesel_migrations;
 mod api;
 mod commands;
 mod config;
+mod downloads;
 mod error;
+mod fsck;
+mod gc;
 mod git_auth;
+mod index;
 mod metadata;
+mod migrate;
 mod models;
+mod rebuild;
 mod repository;
 mod schema;
 mod storage;
 mod types;
 mod utils;
+mod verify;
 
 pub use commands::{Commands, Server};
 
-use std::fs::File;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 use crate::config::Config;
+use crate::downloads::Downloads;
 use crate::error::Error;
-use crate::metadata::{Dependency, Metadata};
-use crate::repository::Repository;
-use crate::storage::Storage;
-use crate::types::CrateName;
+use crate::index::{Index, IndexMode};
+use crate::metadata::Dependency;
+use crate::models::version::Version;
+use crate::storage::{Cached, DownloadMode, Replicated, Storage};
 
 use diesel::pg::PgConnection;
 use diesel::r2d2::{ConnectionManager, Pool};
-use semver::Version;
 
 embed_migrations!("migrations");
 
 #[derive(Clone)]
 pub struct Application {
     pub pool: Pool<ConnectionManager<PgConnection>>,
-    pub storage: Storage,
-    index: Arc<Mutex<Repository>>,
+    pub storage: Arc<dyn Storage>,
+    pub replication: Option<Arc<Replicated>>,
+    pub cache: Option<Arc<Cached>>,
+    pub download_mode: DownloadMode,
+    pub downloads: Arc<Downloads>,
+    pub index: Arc<dyn Index>,
     pub max_upload_size: u64,
+    pub admin_token: Option<String>,
+    pub hosted_index_auth: bool,
     config: Config,
 }
 
@@ -53,37 +68,90 @@ impl Application {
 
         embedded_migrations::run(&conn).unwrap();
 
-        let storage = Storage::new(&server);
+        let encrypted = server.storage_opts.encryption_opts.is_enabled();
+        if encrypted && server.download_mode == DownloadMode::Redirect {
+            return Err(Error::EncryptionRequiresProxy);
+        }
+
+        let cached = server.cache_path.is_some();
+        if cached && server.download_mode == DownloadMode::Redirect {
+            return Err(Error::CacheRequiresProxy);
+        }
 
-        let checkout_path = match server.checkout_path {
-            Some(ref checkout_path) => checkout_path.to_owned(),
-            None => tempfile::TempDir::new()?.into_path(),
+        // The cache sits below encryption so cached tarballs stay encrypted.
+        let (backend, cache) = match server.cache_path {
+            Some(ref cache_path) => {
+                let cache = Arc::new(Cached::new(
+                    storage::backend(&server.storage_opts)?,
+                    cache_path.to_path_buf(),
+                    server.cache_size,
+                )?);
+                (cache.clone() as Arc<dyn Storage>, Some(cache))
+            }
+            None => (storage::backend(&server.storage_opts)?, None),
         };
 
-        let index = Arc::new(Mutex::new(Repository::open(
-            &server.index_location,
-            &checkout_path,
-        )?));
+        let storage = storage::encrypted(backend, &server.storage_opts.encryption_opts)?;
+
+        let (storage, replication) = match server.replica {
+            Some(ref location) => {
+                let replica = location.open(
+                    &server.storage_opts.s3_opts,
+                    &server.storage_opts.encryption_opts,
+                )?;
+                let replicated = Arc::new(Replicated::new(storage, replica));
+                storage::replicated::spawn_replicator(
+                    replicated.clone(),
+                    Duration::from_secs(server.replica_retry_interval),
+                );
+                (replicated.clone() as Arc<dyn Storage>, Some(replicated))
+            }
+            None => (storage, None),
+        };
 
-        let config_file = File::open(checkout_path.join("config.json"))?;
+        let index = index::open(&server.index_opts, Some(pool.clone()))?;
 
-        let config = Config::open(config_file, &server.index_location)?;
+        // Entries of versions published before they were kept on their rows
+        // are needed to rebuild the git index from the database. Reading the
+        // whole index is skipped once every row has its entry, or is known
+        // not to have one.
+        if server.index_opts.index_mode != IndexMode::Database && Version::any_unindexed(&conn)? {
+            index::import_metadata(&conn, &index.entries()?)?;
+        }
+
+        if let Some(hours) = server.index_squash_interval {
+            index::spawn_squasher(index.clone(), Duration::from_secs(hours * 60 * 60));
+        }
+
+        let config_file = index
+            .file(Path::new("config.json"))?
+            .ok_or_else(|| Error::IO(io::ErrorKind::NotFound.into()))?;
+
+        let registry_url = server.index_opts.registry_url().unwrap_or_default();
+        let config = Config::open(&config_file.content[..], &registry_url)?;
+
+        let downloads = Arc::new(Downloads::default());
+        downloads::spawn_flusher(
+            downloads.clone(),
+            pool.clone(),
+            Duration::from_secs(server.downloads_flush_interval),
+        );
 
         Ok(Application {
             pool,
             storage,
+            replication,
+            cache,
+            download_mode: server.download_mode,
+            downloads,
             index,
             max_upload_size: server.max_upload_size,
+            admin_token: server.admin_token.clone(),
+            hosted_index_auth: server.hosted_index_auth,
             config,
         })
     }
 
-    pub fn lock_index(&self) -> Result<MutexGuard<'_, Repository>, Error> {
-        let repo = self.index.lock().unwrap();
-        repo.reset_head()?;
-        Ok(repo)
-    }
-
     pub fn dependency_registry_allowed(&self, dependencies: &[Dependency]) -> Result<(), Error> {
         for dependency in dependencies {
             if let Some(ref registry) = dependency.registry {
@@ -103,64 +171,3 @@ pub(crate) fn make_pool(db_url: &str) -> Result<Pool<ConnectionManager<PgConnect
     let manager = ConnectionManager::<PgConnection>::new(db_url);
     Pool::builder().build(manager).map_err(Error::Pool)
 }
-
-pub fn add_crate(app: &Application, metadata: &Metadata) -> Result<(), Error> {
-    use std::fs::{self, OpenOptions};
-    use std::io::Write;
-
-    let repo = app.lock_index()?;
-
-    let dst = repo.index_file(&*metadata.name);
-    fs::create_dir_all(dst.parent().unwrap())?;
-
-    let mut file = OpenOptions::new().append(true).create(true).open(&dst)?;
-    serde_json::to_writer(&mut file, metadata)?;
-    file.write_all(b"\n")?;
-
-    repo.commit_and_push(
-        &format!("Updating crate `{}#{}`", metadata.name, metadata.vers),
-        &repo.relative_index_file(&metadata.name),
-    )?;
-
-    Ok(())
-}
-
-pub fn yank_crate(
-    app: &Application,
-    name: &CrateName,
-    version: &Version,
-    yanked: bool,
-) -> Result<(), Error> {
-    use std::fs;
-
-    let repo = app.lock_index()?;
-
-    let dst = repo.index_file(&name);
-
-    let prev = fs::read_to_string(&dst)?;
-    let new = prev
-        .lines()
-        .map(|line| {
-            let mut git_crate = serde_json::from_str::<Metadata>(line)?;
-            if &git_crate.name != name || git_crate.vers != *version {
-                return Ok(line.to_string());
-            }
-            git_crate.yanked = yanked;
-            Ok(serde_json::to_string(&git_crate)?)
-        })
-        .collect::<Result<Vec<_>, Error>>();
-    let new = new?.join("\n") + "\n";
-    fs::write(&dst, new.as_bytes())?;
-
-    repo.commit_and_push(
-        &format!(
-            "{} crate `{}#{}`",
-            if yanked { "Yanking" } else { "Unyanking" },
-            name,
-            version
-        ),
-        &repo.relative_index_file(name),
-    )?;
-
-    Ok(())
-}
@@ -6,5 +6,8 @@ fn main() {
 
     let commands = Commands::from_args();
 
-    commands.run().unwrap();
+    if let Err(err) = commands.run() {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
 }
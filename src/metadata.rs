@@ -5,7 +5,7 @@ use crate::types::CrateName;
 use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Metadata {
     pub name: CrateName,
     pub vers: Version,
@@ -14,6 +14,40 @@ pub struct Metadata {
     pub features: HashMap<String, Vec<String>>,
     pub yanked: bool,
     pub links: Option<String>,
+    /// Features using namespaced (`dep:`) or weak (`?/`) dependency
+    /// features, kept apart so cargo versions that don't understand them can
+    /// still use the entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub features2: Option<HashMap<String, Vec<String>>>,
+    /// The version of the entry format, 2 if there are `features2`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rust_version: Option<String>,
+}
+
+impl Metadata {
+    /// Splits the features of an upload into those every cargo version
+    /// understands and those that go in `features2`.
+    pub fn split_features(
+        features: HashMap<String, Vec<String>>,
+    ) -> (
+        HashMap<String, Vec<String>>,
+        Option<HashMap<String, Vec<String>>>,
+    ) {
+        let (features2, features): (HashMap<_, _>, HashMap<_, _>) =
+            features.into_iter().partition(|(_, values)| {
+                values
+                    .iter()
+                    .any(|value| value.starts_with("dep:") || value.contains("?/"))
+            });
+
+        if features2.is_empty() {
+            (features, None)
+        } else {
+            (features, Some(features2))
+        }
+    }
 }
 
 impl PartialEq for Metadata {
@@ -24,7 +58,7 @@ impl PartialEq for Metadata {
 
 impl Eq for Metadata {}
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Dependency {
     pub name: String,
     pub req: VersionReq,
@@ -44,3 +78,74 @@ pub enum Kind {
     Build,
     Dev,
 }
+
+/// The index entry of a crate version whose tarball is `content`, for tests.
+#[cfg(test)]
+pub(crate) fn entry(name: &str, vers: &str, content: &[u8]) -> Metadata {
+    use sha2::{Digest, Sha256};
+
+    serde_json::from_value(serde_json::json!({
+        "name": name,
+        "vers": vers,
+        "deps": [],
+        "cksum": format!("{:x}", Sha256::digest(content)),
+        "features": {},
+        "yanked": false,
+        "links": null,
+    }))
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(features: &[(&str, &[&str])]) -> HashMap<String, Vec<String>> {
+        features
+            .iter()
+            .map(|(name, values)| {
+                let values = values.iter().map(|value| value.to_string()).collect();
+                (name.to_string(), values)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_namespaced_and_weak_features() {
+        let (plain, features2) = Metadata::split_features(features(&[
+            ("default", &["std"]),
+            ("std", &["serde/std"]),
+            ("serde", &["dep:serde"]),
+            ("derive", &["serde?/derive"]),
+        ]));
+
+        assert_eq!(
+            plain,
+            features(&[("default", &["std"]), ("std", &["serde/std"])])
+        );
+        assert_eq!(
+            features2,
+            Some(features(&[
+                ("serde", &["dep:serde"]),
+                ("derive", &["serde?/derive"]),
+            ]))
+        );
+
+        let (_, features2) = Metadata::split_features(features(&[("default", &[])]));
+        assert_eq!(features2, None);
+    }
+
+    #[test]
+    fn leave_out_v2_fields_when_unset() {
+        let metadata = serde_json::from_str::<Metadata>(
+            r#"{"name":"foo","vers":"1.0.0","deps":[],"cksum":"abc","features":{},"yanked":false,"links":null}"#,
+        )
+        .unwrap();
+        assert_eq!(metadata.v, None);
+
+        let json = serde_json::to_string(&metadata).unwrap();
+        assert!(!json.contains("features2"));
+        assert!(!json.contains(r#""v""#));
+        assert!(!json.contains("rust_version"));
+    }
+}
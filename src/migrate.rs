@@ -0,0 +1,217 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::error::Error;
+use crate::metadata::Metadata;
+use crate::storage::{self, Storage};
+
+use sha2::{Digest, Sha256};
+
+#[derive(Debug)]
+pub struct Failure {
+    pub name: String,
+    pub vers: String,
+    pub reason: String,
+}
+
+/// The outcome of copying every crate between two storage backends.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub copied: usize,
+    pub skipped: usize,
+    pub failed: Vec<Failure>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for failure in &self.failed {
+            writeln!(
+                f,
+                "failed: {} {}: {}",
+                failure.name, failure.vers, failure.reason
+            )?;
+        }
+        writeln!(
+            f,
+            "{} copied, {} already present, {} failed",
+            self.copied,
+            self.skipped,
+            self.failed.len()
+        )
+    }
+}
+
+enum Outcome {
+    Copied,
+    Skipped,
+}
+
+/// Copies the tarball of every index entry from one storage backend to
+/// another using `jobs` threads.
+///
+/// Tarballs already in `to` with the right checksum are skipped, so an
+/// interrupted migration can be resumed by running it again.
+pub fn migrate_storage(
+    entries: Vec<Metadata>,
+    from: Arc<dyn Storage>,
+    to: Arc<dyn Storage>,
+    jobs: usize,
+) -> Summary {
+    let queue = Arc::new(Mutex::new(entries.into_iter()));
+    let summary = Arc::new(Mutex::new(Summary::default()));
+
+    let workers = (0..jobs.max(1))
+        .map(|_| {
+            let queue = queue.clone();
+            let summary = summary.clone();
+            let from = from.clone();
+            let to = to.clone();
+
+            thread::spawn(move || loop {
+                let metadata = match queue.lock().unwrap().next() {
+                    Some(metadata) => metadata,
+                    None => break,
+                };
+
+                let outcome = copy(&metadata, &*from, &*to);
+
+                let mut summary = summary.lock().unwrap();
+                match outcome {
+                    Ok(Outcome::Copied) => {
+                        info!("Copied {} {}", metadata.name, metadata.vers);
+                        summary.copied += 1;
+                    }
+                    Ok(Outcome::Skipped) => summary.skipped += 1,
+                    Err(err) => {
+                        warn!(
+                            "Failed to copy {} {}: {}",
+                            metadata.name, metadata.vers, err
+                        );
+                        summary.failed.push(Failure {
+                            name: metadata.name.to_string(),
+                            vers: metadata.vers.to_string(),
+                            reason: err.to_string(),
+                        });
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    Arc::try_unwrap(summary).ok().unwrap().into_inner().unwrap()
+}
+
+fn copy(metadata: &Metadata, from: &dyn Storage, to: &dyn Storage) -> Result<Outcome, Error> {
+    let name = metadata.name.to_string();
+    let vers = metadata.vers.to_string();
+
+    let exists = to.exists(&name, &vers)?;
+    if exists {
+        let intact = match storage::checksum(to, &name, &vers) {
+            Ok(actual) => actual == metadata.cksum,
+            // Backends that verify tarballs as they're read refuse to return
+            // one that doesn't match its recorded checksum, so it's replaced
+            Err(Error::IO(ref err))
+                if err.kind() == io::ErrorKind::InvalidData
+                    && err
+                        .get_ref()
+                        .map_or(false, |err| err.is::<storage::Corrupted>()) =>
+            {
+                false
+            }
+            Err(err) => return Err(err),
+        };
+        if intact {
+            return Ok(Outcome::Skipped);
+        }
+    }
+
+    let mut content = Vec::new();
+    from.stream(&name, &vers)?.body.read_to_end(&mut content)?;
+
+    if format!("{:x}", Sha256::digest(&content)) != metadata.cksum {
+        return Err(Error::ChecksumMismatch(name, vers));
+    }
+
+    if exists {
+        to.replace(&name, &vers, &content)?;
+    } else {
+        to.put(&name, &vers, &content)?;
+    }
+
+    if storage::checksum(to, &name, &vers)? != metadata.cksum {
+        return Err(Error::ChecksumMismatch(name, vers));
+    }
+
+    Ok(Outcome::Copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::commands::LocalOpts;
+    use crate::metadata::entry;
+    use crate::storage::memory::Memory;
+    use crate::storage::Local;
+
+    #[test]
+    fn copy_between_backends() {
+        let from = Arc::new(Memory::default());
+        from.put("foo", "1.0.0", b"foo").unwrap();
+        from.put("bar", "1.0.0", b"bar").unwrap();
+        from.put("baz", "1.0.0", b"corrupted").unwrap();
+
+        let to = Arc::new(Memory::default());
+        to.put("bar", "1.0.0", b"bar").unwrap();
+
+        let entries = vec![
+            entry("foo", "1.0.0", b"foo"),
+            entry("bar", "1.0.0", b"bar"),
+            entry("baz", "1.0.0", b"baz"),
+        ];
+
+        let summary = migrate_storage(entries, from, to.clone(), 2);
+
+        assert_eq!(summary.copied, 1);
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].name, "baz");
+        assert!(to.exists("foo", "1.0.0").unwrap());
+        assert!(!to.exists("baz", "1.0.0").unwrap());
+    }
+
+    #[test]
+    fn replace_corrupted_local_tarballs() {
+        let from = Arc::new(Memory::default());
+        from.put("foo", "1.0.0", b"foo").unwrap();
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let to = Arc::new(
+            Local::new(&LocalOpts {
+                local_base_path: Some(dir.path().to_path_buf()),
+            })
+            .unwrap(),
+        );
+        to.put("foo", "1.0.0", b"foo").unwrap();
+        // No longer matches the checksum recorded alongside it
+        fs::write(dir.path().join("crates/foo/foo-1.0.0.crate"), b"bar").unwrap();
+
+        let summary = migrate_storage(vec![entry("foo", "1.0.0", b"foo")], from, to.clone(), 1);
+
+        assert_eq!(summary.copied, 1);
+        assert!(summary.failed.is_empty());
+        assert_eq!(
+            storage::checksum(&*to, "foo", "1.0.0").unwrap(),
+            format!("{:x}", Sha256::digest(b"foo"))
+        );
+    }
+}
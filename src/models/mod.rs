@@ -3,3 +3,4 @@ pub mod krateowner;
 pub mod owner;
 pub mod token;
 pub mod version;
+pub mod version_downloads;
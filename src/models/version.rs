@@ -1,9 +1,14 @@
 use crate::error::Error;
+use crate::metadata::Metadata;
 use crate::models::krate::Krate;
 use crate::schema::version;
 
+use chrono::{NaiveDateTime, Utc};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
+use diesel::sql_types::Text;
+
+sql_function!(fn lower(x: Text) -> Text);
 
 #[derive(AsChangeset, Associations, Debug, Identifiable, Queryable)]
 #[belongs_to(parent = "Krate")]
@@ -13,9 +18,87 @@ pub struct Version {
     pub krate_id: i32,
     pub vers: String,
     pub yanked: bool,
+    pub cksum: Option<String>,
+    pub created_at: NaiveDateTime,
+    /// The index entry as JSON, set once the version has been added to the
+    /// index. Kept in every index mode so the git index can be rebuilt.
+    pub metadata: Option<String>,
+    pub updated_at: NaiveDateTime,
+    /// Set if the index had no entry to import for the version, e.g. as its
+    /// publish failed, so the index isn't read on every start to look for it.
+    pub missing_from_index: bool,
 }
 
 impl Version {
+    /// Loads every version along with the name of its crate, in the order
+    /// they were published.
+    pub fn all_with_crate_name(conn: &PgConnection) -> Result<Vec<(String, Self)>, Error> {
+        use crate::schema::krate;
+
+        version::table
+            .inner_join(krate::table)
+            .order(version::id)
+            .select((krate::name, version::all_columns))
+            .load::<(String, Version)>(conn)
+            .map_err(Error::DB)
+    }
+
+    /// Loads every version that has been added to the index, in the order
+    /// they were published.
+    pub fn indexed(conn: &PgConnection) -> Result<Vec<Self>, Error> {
+        version::table
+            .filter(version::metadata.is_not_null())
+            .order(version::id)
+            .load::<Version>(conn)
+            .map_err(Error::DB)
+    }
+
+    /// Whether any version hasn't had its index entry recorded yet, other
+    /// than those the index was already found to be missing.
+    pub fn any_unindexed(conn: &PgConnection) -> Result<bool, Error> {
+        diesel::select(diesel::dsl::exists(
+            version::table
+                .filter(version::metadata.is_null())
+                .filter(version::missing_from_index.eq(false)),
+        ))
+        .get_result(conn)
+        .map_err(Error::DB)
+    }
+
+    /// Loads the indexed versions of a crate, ignoring the case of its name
+    /// as index paths are lowercase.
+    pub fn indexed_by_crate_name(conn: &PgConnection, name: &str) -> Result<Vec<Self>, Error> {
+        use crate::schema::krate;
+
+        version::table
+            .inner_join(krate::table)
+            .filter(lower(krate::name).eq(name.to_lowercase()))
+            .filter(version::metadata.is_not_null())
+            .order(version::id)
+            .select(version::all_columns)
+            .load::<Version>(conn)
+            .map_err(Error::DB)
+    }
+
+    /// The index entry for this version, with its current yanked status.
+    pub fn index_entry(&self) -> Result<Option<Metadata>, Error> {
+        match self.metadata {
+            Some(ref metadata) => {
+                let mut metadata = serde_json::from_str::<Metadata>(metadata)?;
+                metadata.yanked = self.yanked;
+                Ok(Some(metadata))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn by_crate_id(conn: &PgConnection, krate_id: i32) -> Result<Vec<Self>, Error> {
+        version::table
+            .filter(version::krate_id.eq(krate_id))
+            .load::<Version>(conn)
+            .map_err(Error::DB)
+    }
+
     pub fn by_crate_id_and_version(
         conn: &PgConnection,
         krate_id: i32,
@@ -37,6 +120,7 @@ impl Version {
         let yanked_version = YankedVersion {
             id: self.id,
             yanked,
+            updated_at: Utc::now().naive_utc(),
         };
 
         yanked_version
@@ -44,6 +128,31 @@ impl Version {
             .map_err(Error::DB)?;
         Ok(())
     }
+
+    pub fn set_metadata(&self, conn: &PgConnection, metadata: &str) -> Result<(), Error> {
+        diesel::update(self)
+            .set((
+                version::metadata.eq(metadata),
+                version::updated_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    pub fn set_missing_from_index(&self, conn: &PgConnection) -> Result<(), Error> {
+        diesel::update(self)
+            .set(version::missing_from_index.eq(true))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    pub fn delete(&self, conn: &PgConnection) -> Result<(), Error> {
+        diesel::delete(self).execute(conn)?;
+
+        Ok(())
+    }
 }
 
 #[derive(Insertable)]
@@ -52,6 +161,7 @@ pub struct NewVersion<'a> {
     pub krate_id: i32,
     pub vers: &'a str,
     pub yanked: bool,
+    pub cksum: &'a str,
 }
 
 impl<'a> NewVersion<'a> {
@@ -68,4 +178,35 @@ impl<'a> NewVersion<'a> {
 pub struct YankedVersion {
     pub id: i32,
     pub yanked: bool,
+    pub updated_at: NaiveDateTime,
+}
+
+/// A version of the crate `name` as `Version::all_with_crate_name` loads it,
+/// for tests. Its index entry is only recorded if it's `indexed`.
+#[cfg(test)]
+pub(crate) fn row(
+    id: i32,
+    name: &str,
+    vers: &str,
+    yanked: bool,
+    indexed: bool,
+) -> (String, Version) {
+    let metadata = format!(
+        r#"{{"name":"{}","vers":"{}","deps":[],"cksum":"abc","features":{{}},"yanked":false,"links":null}}"#,
+        name, vers
+    );
+
+    let version = Version {
+        id,
+        krate_id: 1,
+        vers: vers.to_owned(),
+        yanked,
+        cksum: Some("abc".to_owned()),
+        created_at: Utc::now().naive_utc(),
+        metadata: if indexed { Some(metadata) } else { None },
+        updated_at: Utc::now().naive_utc(),
+        missing_from_index: false,
+    };
+
+    (name.to_owned(), version)
 }
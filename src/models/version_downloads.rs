@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+use crate::error::Error;
+use crate::schema::version_downloads;
+
+use chrono::NaiveDate;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{Date, Integer, Text};
+
+#[derive(Debug, Queryable)]
+pub struct VersionDownloads {
+    pub version_id: i32,
+    pub date: NaiveDate,
+    pub downloads: i32,
+}
+
+impl VersionDownloads {
+    /// Adds `count` downloads of a crate version on `date`. The crate name
+    /// is matched in any case, as it comes from the download URL.
+    pub fn increment(
+        conn: &PgConnection,
+        name: &str,
+        vers: &str,
+        date: NaiveDate,
+        count: i32,
+    ) -> Result<(), Error> {
+        diesel::sql_query(
+            "INSERT INTO version_downloads (version_id, date, downloads) \
+             SELECT version.id, $1, $2 FROM version \
+             INNER JOIN krate ON krate.id = version.krate_id \
+             WHERE lower(krate.name) = lower($3) AND version.vers = $4 \
+             ON CONFLICT (version_id, date) \
+             DO UPDATE SET downloads = version_downloads.downloads + EXCLUDED.downloads",
+        )
+        .bind::<Date, _>(date)
+        .bind::<Integer, _>(count)
+        .bind::<Text, _>(name)
+        .bind::<Text, _>(vers)
+        .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Total downloads of every version of a crate, keyed by version id.
+    pub fn totals_by_crate_id(
+        conn: &PgConnection,
+        krate_id: i32,
+    ) -> Result<HashMap<i32, i64>, Error> {
+        use crate::schema::version;
+        use diesel::dsl::sum;
+
+        let totals = version_downloads::table
+            .inner_join(version::table)
+            .filter(version::krate_id.eq(krate_id))
+            .group_by(version_downloads::version_id)
+            .select((
+                version_downloads::version_id,
+                sum(version_downloads::downloads),
+            ))
+            .load::<(i32, Option<i64>)>(conn)?;
+
+        Ok(totals
+            .into_iter()
+            .map(|(version_id, total)| (version_id, total.unwrap_or(0)))
+            .collect())
+    }
+
+    /// Daily downloads of every version of a crate since `since`, along with
+    /// the version number.
+    pub fn by_crate_id_since(
+        conn: &PgConnection,
+        krate_id: i32,
+        since: NaiveDate,
+    ) -> Result<Vec<(String, Self)>, Error> {
+        use crate::schema::version;
+
+        version_downloads::table
+            .inner_join(version::table)
+            .filter(version::krate_id.eq(krate_id))
+            .filter(version_downloads::date.ge(since))
+            .order((version_downloads::date.desc(), version::vers))
+            .select((version::vers, version_downloads::all_columns))
+            .load::<(String, VersionDownloads)>(conn)
+            .map_err(Error::DB)
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::error::Error;
+use crate::index::relative_index_file;
+use crate::models::version::Version;
+use crate::repository::Repository;
+
+/// The index files regenerated from the database.
+#[derive(Debug, Default)]
+pub struct Rebuild {
+    /// Content of each index file, by its path relative to the root of the
+    /// index.
+    pub files: BTreeMap<PathBuf, Vec<u8>>,
+    pub versions: usize,
+    /// Versions without a recorded entry, i.e. publishes that failed
+    /// part-way.
+    pub skipped: Vec<(String, String)>,
+}
+
+impl fmt::Display for Rebuild {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, vers) in &self.skipped {
+            writeln!(f, "skipped {} {}: no index entry recorded", name, vers)?;
+        }
+        writeln!(
+            f,
+            "{} index files, {} versions, {} skipped",
+            self.files.len(),
+            self.versions,
+            self.skipped.len()
+        )
+    }
+}
+
+/// Regenerates every index file from the entries recorded on the version
+/// rows, which must be in the order they were published.
+pub fn plan<'a>(rows: impl IntoIterator<Item = &'a (String, Version)>) -> Result<Rebuild, Error> {
+    let mut rebuild = Rebuild::default();
+
+    for (name, version) in rows {
+        let metadata = match version.index_entry()? {
+            Some(metadata) => metadata,
+            None => {
+                rebuild
+                    .skipped
+                    .push((name.to_owned(), version.vers.to_owned()));
+                continue;
+            }
+        };
+
+        let file = rebuild
+            .files
+            .entry(relative_index_file(name))
+            .or_insert_with(Vec::new);
+        serde_json::to_writer(&mut *file, &metadata)?;
+        file.push(b'\n');
+        rebuild.versions += 1;
+    }
+
+    Ok(rebuild)
+}
+
+/// Writes the index files and `config.json` into a fresh repository and
+/// replaces the remote branch with them in a single commit.
+pub fn write(rebuild: &Rebuild, config: &[u8], repo: &Repository) -> Result<(), Error> {
+    let root = repo.checkout_path();
+
+    fs::write(root.join("config.json"), config)?;
+    for (path, content) in &rebuild.files {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, content)?;
+    }
+
+    repo.commit_all_and_force_push(&format!(
+        "Rebuilding the index from {} versions",
+        rebuild.versions
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::version::row;
+
+    #[test]
+    fn regenerate_index_files_from_rows() {
+        let rebuild = plan(&[
+            row(1, "foo", "0.1.0", false, true),
+            row(2, "Serde", "1.0.0", false, true),
+            row(3, "foo", "0.2.0", true, true),
+            row(4, "foo", "0.3.0", false, false),
+        ])
+        .unwrap();
+
+        assert_eq!(rebuild.versions, 3);
+        assert_eq!(
+            rebuild.skipped,
+            vec![("foo".to_owned(), "0.3.0".to_owned())]
+        );
+
+        let foo = String::from_utf8(rebuild.files[&PathBuf::from("3/f/foo")].clone()).unwrap();
+        let lines = foo.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(r#""vers":"0.1.0""#));
+        assert!(lines[0].contains(r#""yanked":false"#));
+        assert!(lines[1].contains(r#""vers":"0.2.0""#));
+        assert!(lines[1].contains(r#""yanked":true"#));
+
+        assert!(rebuild.files.contains_key(&PathBuf::from("se/rd/serde")));
+    }
+}
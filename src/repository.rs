@@ -1,37 +1,255 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
 
+use crate::commands::GitOpts;
 use crate::error::Error;
-use crate::git_auth::with_authentication;
+use crate::git_auth::{with_authentication, GitCredentials};
+use crate::metadata::Metadata;
 
 pub struct Repository {
     checkout_path: PathBuf,
     repository: git2::Repository,
+    remote: Option<Remote>,
+    branch: String,
+    push_refspec: String,
+    upstream: String,
+    archive_prefix: Option<String>,
+    push_retries: u32,
+    shallow: bool,
+    committer_name: Option<String>,
+    committer_email: Option<String>,
+    signing_key: Option<(SigningFormat, String)>,
+}
+
+/// How index commits are signed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SigningFormat {
+    /// With a GPG key, using `gpg`.
+    Gpg,
+    /// With an SSH key, using `ssh-keygen`.
+    Ssh,
+}
+
+impl FromStr for SigningFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "gpg" => Ok(SigningFormat::Gpg),
+            "ssh" => Ok(SigningFormat::Ssh),
+            _ => Err(Error::UnknownSigningFormat(format.to_owned())),
+        }
+    }
+}
+
+/// Where the index is fetched from and pushed to, unless pallet hosts it
+/// itself.
+struct Remote {
     url: String,
+    name: String,
+    credentials: GitCredentials,
 }
 
 impl Repository {
-    pub fn open(url: &str, checkout_path: &Path) -> Result<Self, Error> {
-        // let checkout_path = TempDir::new()?;
-
-        // info!("Checkout path: {}", checkout_path.path().display());
+    /// Opens the index in `checkout_path`, reusing the checkout left by a
+    /// previous run if there is one. A checkout that can't be brought up to
+    /// date is cloned again.
+    pub fn open(url: &str, checkout_path: &Path, opts: &GitOpts) -> Result<Self, Error> {
+        // Only a checkout git can't read is replaced. One of another index,
+        // or one that can't be brought up to date, is left for an operator.
+        if checkout_path.join(".git").exists() {
+            let reused = match git2::Repository::open(checkout_path) {
+                Ok(repository) => Self::reuse(url, checkout_path, repository, opts)?,
+                Err(err) => Err(err),
+            };
+            match reused {
+                Ok(repository) => {
+                    info!("Reusing the checkout in {}", checkout_path.display());
+                    return Ok(repository);
+                }
+                Err(err) => {
+                    warn!(
+                        "Cloning the index again, the checkout in {} is corrupted: {}",
+                        checkout_path.display(),
+                        err
+                    );
+                    fs::remove_dir_all(checkout_path)?;
+                }
+            }
+        }
 
         let cfg = git2::Config::new()?;
+        let remote = remote(url, opts)?;
 
-        let repository = with_authentication(url, &cfg, |f| {
-            let mut cb = git2::RemoteCallbacks::new();
-            cb.credentials(f);
-            let mut opts = git2::FetchOptions::new();
-            opts.remote_callbacks(cb);
-            let mut rb = git2::build::RepoBuilder::new();
-            rb.fetch_options(opts);
-            rb.clone(url, checkout_path)
-        })?;
+        let repository = if opts.index_shallow {
+            let mut command = remote.credentials.git_command();
+            command
+                .args(&["clone", "--depth", "1", "--branch"])
+                .arg(&opts.index_branch)
+                .arg("--origin")
+                .arg(&opts.index_remote)
+                .arg(url)
+                .arg(checkout_path);
+            git(command)?;
+            git2::Repository::open(checkout_path)?
+        } else {
+            with_authentication(url, &cfg, &remote.credentials, |f| {
+                let mut cb = git2::RemoteCallbacks::new();
+                cb.credentials(f);
+                let mut fetch_opts = git2::FetchOptions::new();
+                fetch_opts.remote_callbacks(cb);
+                let mut rb = git2::build::RepoBuilder::new();
+                rb.fetch_options(fetch_opts);
+                rb.branch(&opts.index_branch);
+                rb.remote_create(|repo, _name, url| repo.remote(&opts.index_remote, url));
+                rb.clone(url, checkout_path)
+            })?
+        };
+
+        Ok(Self::new(checkout_path, repository, Some(remote), opts))
+    }
+
+    /// Brings an existing checkout of `url` up to date with the remote. The
+    /// inner error is returned if the checkout is corrupted and can't be reset.
+    fn reuse(
+        url: &str,
+        checkout_path: &Path,
+        repository: git2::Repository,
+        opts: &GitOpts,
+    ) -> Result<Result<Self, git2::Error>, Error> {
+        let remote_url = repository
+            .find_remote(&opts.index_remote)?
+            .url()
+            .map(str::to_owned);
+        if remote_url.as_ref().map(String::as_str) != Some(url) {
+            return Err(Error::InvalidCheckout(format!(
+                "{} in {} is a checkout of {}, not {}",
+                opts.index_remote,
+                checkout_path.display(),
+                remote_url.unwrap_or_default(),
+                url
+            )));
+        }
+
+        let repository = Self::new(checkout_path, repository, Some(remote(url, opts)?), opts);
+        let target = repository.fetch()?;
 
-        Ok(Self {
+        // The branch may not have been checked out before
+        let reset = || -> Result<(), git2::Error> {
+            let branch = format!("refs/heads/{}", repository.branch);
+            repository
+                .repository
+                .reference(&branch, target, true, "Reusing the checkout")?;
+            repository.repository.set_head(&branch)?;
+            let obj = repository.repository.find_object(target, None)?;
+            repository
+                .repository
+                .reset(&obj, git2::ResetType::Hard, None)
+        };
+        match reset() {
+            Ok(()) => Ok(Ok(repository)),
+            Err(err) => Ok(Err(err)),
+        }
+    }
+
+    fn new(
+        checkout_path: &Path,
+        repository: git2::Repository,
+        remote: Option<Remote>,
+        opts: &GitOpts,
+    ) -> Self {
+        Self {
             checkout_path: checkout_path.to_path_buf(),
+            // A shallow checkout stays shallow without the flag
+            shallow: opts.index_shallow || repository.is_shallow(),
             repository,
-            url: url.to_owned(),
-        })
+            remote,
+            branch: opts.index_branch.to_owned(),
+            push_refspec: opts.push_refspec(),
+            upstream: push_destination(&opts.push_refspec()),
+            archive_prefix: opts.index_squash_archive.clone(),
+            push_retries: opts.index_push_retries,
+            committer_name: opts.index_committer_name.clone(),
+            committer_email: opts.index_committer_email.clone(),
+            signing_key: opts
+                .index_signing_key
+                .as_ref()
+                .map(|key| (opts.index_signing_format, key.to_owned())),
+        }
+    }
+
+    /// Creates an empty repository in `checkout_path` that pushes to `url`,
+    /// without fetching anything from it.
+    pub fn init(url: &str, checkout_path: &Path, opts: &GitOpts) -> Result<Self, Error> {
+        let repository = git2::Repository::init(checkout_path)?;
+        repository.set_head(&format!("refs/heads/{}", opts.index_branch))?;
+        repository.remote(&opts.index_remote, url)?;
+
+        Ok(Self::new(
+            checkout_path,
+            repository,
+            Some(remote(url, opts)?),
+            opts,
+        ))
+    }
+
+    /// Opens the repository pallet hosts itself in `checkout_path`, creating
+    /// it with `config` as its `config.json` if there isn't one yet. Commits
+    /// are never pushed anywhere.
+    pub fn host(checkout_path: &Path, opts: &GitOpts, config: &[u8]) -> Result<Self, Error> {
+        match git2::Repository::open(checkout_path) {
+            Ok(repository) => Ok(Self::new(checkout_path, repository, None, opts)),
+            Err(ref err) if err.code() == git2::ErrorCode::NotFound => {
+                info!("Creating the index in {}", checkout_path.display());
+                let repository = git2::Repository::init(checkout_path)?;
+                repository.set_head(&format!("refs/heads/{}", opts.index_branch))?;
+
+                let repository = Self::new(checkout_path, repository, None, opts);
+                fs::write(checkout_path.join("config.json"), config)?;
+                repository.commit_all_and_force_push("Creating the index")?;
+
+                Ok(repository)
+            }
+            Err(err) => Err(Error::Git(err)),
+        }
+    }
+
+    /// Creates the bare repository a `file://` URL points at, if there isn't
+    /// one there yet, returning whether it did.
+    pub fn create_local_remote(url: &str, opts: &GitOpts) -> Result<bool, Error> {
+        if !url.starts_with("file://") {
+            return Ok(false);
+        }
+
+        let path = Path::new(&url["file://".len()..]);
+
+        if path.exists() {
+            return Ok(false);
+        }
+
+        let repository = git2::Repository::init_bare(path)?;
+        repository.set_head(&format!("refs/heads/{}", opts.index_branch))?;
+
+        Ok(true)
+    }
+
+    /// How many times a push rejected as non-fast-forward should be retried.
+    pub fn push_retries(&self) -> u32 {
+        self.push_retries
+    }
+
+    /// Whether pallet hosts the repository itself rather than pushing to a
+    /// remote.
+    pub fn is_hosted(&self) -> bool {
+        self.remote.is_none()
+    }
+
+    pub fn checkout_path(&self) -> &Path {
+        &self.checkout_path
     }
 
     pub fn index_file(&self, name: &str) -> PathBuf {
@@ -39,20 +257,64 @@ impl Repository {
     }
 
     pub fn relative_index_file(&self, name: &str) -> PathBuf {
-        let name = name.to_lowercase();
-        match name.len() {
-            1 => Path::new("1").join(&name),
-            2 => Path::new("2").join(&name),
-            3 => Path::new("3").join(&name[..1]).join(&name),
-            _ => Path::new(&name[0..2]).join(&name[2..4]).join(&name),
+        crate::index::relative_index_file(name)
+    }
+
+    /// Reads every entry from the index files in the checkout.
+    pub fn entries(&self) -> Result<Vec<Metadata>, Error> {
+        let mut entries = Vec::new();
+        for (_, content) in self.index_files()? {
+            for line in content.lines().filter(|line| !line.trim().is_empty()) {
+                entries.push(serde_json::from_str(line)?);
+            }
         }
+        Ok(entries)
     }
 
-    pub fn commit_and_push(&self, msg: &str, modified_file: &Path) -> Result<(), Error> {
-        debug!("Adding file");
-        // git add $file
+    /// Reads the index files in the checkout, along with their path relative
+    /// to the root of the index.
+    pub fn index_files(&self) -> Result<Vec<(PathBuf, String)>, Error> {
+        let mut files = Vec::new();
+        self.read_index_files(&self.checkout_path, &mut files)?;
+        Ok(files)
+    }
+
+    fn read_index_files(
+        &self,
+        dir: &Path,
+        files: &mut Vec<(PathBuf, String)>,
+    ) -> Result<(), Error> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+
+            // Skip `.git` and any other hidden directories
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+
+            if entry.file_type()?.is_dir() {
+                self.read_index_files(&entry.path(), files)?;
+            } else if dir != self.checkout_path {
+                // Files in the root, e.g. `config.json`, aren't index files
+                let path = entry.path();
+                let content = fs::read_to_string(&path)?;
+                let relative = path
+                    .strip_prefix(&self.checkout_path)
+                    .unwrap()
+                    .to_path_buf();
+                files.push((relative, content));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn commit_and_push(&self, msg: &str, modified_files: &[&Path]) -> Result<(), Error> {
+        debug!("Adding files");
+        // git add $files
         let mut index = self.repository.index()?;
-        index.add_path(modified_file)?;
+        for modified_file in modified_files {
+            index.add_path(modified_file)?;
+        }
         index.write()?;
         let tree_id = index.write_tree()?;
         let tree = self.repository.find_tree(tree_id)?;
@@ -61,42 +323,671 @@ impl Repository {
         // git commit -m "..."
         let head = self.repository.head()?;
         let parent = self.repository.find_commit(head.target().unwrap())?;
-        let sig = self.repository.signature()?;
-        self.repository
-            .commit(Some("HEAD"), &sig, &sig, &msg, &tree, &[&parent])?;
+        let commit = self.commit(msg, &tree, &[&parent])?;
+        self.set_branch(commit, msg)?;
 
         debug!("Pushing");
-        with_authentication(&self.url, &self.repository.config()?, |f| {
-            let mut origin = self.repository.find_remote("origin")?;
+        self.push(&self.push_refspec)
+    }
+
+    /// Commits every file in the checkout as the first commit of the branch
+    /// and force pushes it, replacing the remote branch's history.
+    pub fn commit_all_and_force_push(&self, msg: &str) -> Result<(), Error> {
+        debug!("Adding all files");
+        let mut index = self.repository.index()?;
+        index.add_all(&["*"], git2::IndexAddOption::DEFAULT, None)?;
+        index.write()?;
+        let tree_id = index.write_tree()?;
+        let tree = self.repository.find_tree(tree_id)?;
+
+        debug!("Committing");
+        let commit = self.commit(msg, &tree, &[])?;
+        self.set_branch(commit, msg)?;
+
+        debug!("Force pushing");
+        self.push(&format!("+{}", self.push_refspec))
+    }
+
+    /// Creates a commit without updating any ref, signed if
+    /// `--index-signing-key` is set.
+    fn commit(
+        &self,
+        msg: &str,
+        tree: &git2::Tree,
+        parents: &[&git2::Commit],
+    ) -> Result<git2::Oid, Error> {
+        let sig = self.signature()?;
+
+        let (format, key) = match self.signing_key {
+            Some((format, ref key)) => (format, key),
+            None => {
+                return Ok(self
+                    .repository
+                    .commit(None, &sig, &sig, msg, tree, parents)?)
+            }
+        };
+
+        let content = self
+            .repository
+            .commit_create_buffer(&sig, &sig, msg, tree, parents)?;
+        let content = content
+            .as_str()
+            .ok_or_else(|| Error::Signing("the commit isn't UTF-8".to_owned()))?;
+        let signature = sign(format, key, content.as_bytes())?;
+
+        Ok(self.repository.commit_signed(content, &signature, None)?)
+    }
+
+    /// The committer of index commits: `--index-committer-name` and
+    /// `--index-committer-email`, falling back to git's config and then to
+    /// pallet itself, so commits don't fail where no git user is configured.
+    fn signature(&self) -> Result<git2::Signature<'static>, Error> {
+        let config = self.repository.config()?;
+        let name = match self.committer_name {
+            Some(ref name) => name.to_owned(),
+            None => config
+                .get_string("user.name")
+                .unwrap_or_else(|_| "pallet".to_owned()),
+        };
+        let email = match self.committer_email {
+            Some(ref email) => email.to_owned(),
+            None => config
+                .get_string("user.email")
+                .unwrap_or_else(|_| "pallet@localhost".to_owned()),
+        };
+
+        Ok(git2::Signature::now(&name, &email)?)
+    }
+
+    /// The commit the branch is at.
+    pub fn head_id(&self) -> Result<git2::Oid, Error> {
+        Ok(self
+            .repository
+            .refname_to_id(&format!("refs/heads/{}", self.branch))?)
+    }
+
+    /// Points the branch at `commit`.
+    fn set_branch(&self, commit: git2::Oid, msg: &str) -> Result<(), Error> {
+        self.repository
+            .reference(&format!("refs/heads/{}", self.branch), commit, true, msg)?;
+        Ok(())
+    }
+
+    fn push(&self, refspec: &str) -> Result<(), Error> {
+        let (url, name, credentials) = match self.remote {
+            Some(ref remote) => (&remote.url, &remote.name, &remote.credentials),
+            None => return Ok(()),
+        };
+
+        if self.shallow {
+            return self.push_with_git(credentials, name, &[refspec]);
+        }
+
+        let rejected = RefCell::new(None);
+        let result = with_authentication(url, &self.repository.config()?, credentials, |f| {
+            let mut remote = self.repository.find_remote(name)?;
             let mut cb = git2::RemoteCallbacks::new();
             cb.credentials(f);
+            // The remote reports refs it refused, e.g. protected branches,
+            // here rather than failing the push.
+            cb.push_update_reference(|refname, status| {
+                if let Some(status) = status {
+                    *rejected.borrow_mut() = Some(format!("{}: {}", refname, status));
+                }
+                Ok(())
+            });
 
             let mut opts = git2::PushOptions::new();
             opts.remote_callbacks(cb);
-            origin.push(&["refs/heads/master"], Some(&mut opts))?;
+            remote.push(&[refspec], Some(&mut opts))?;
 
             Ok(())
-        })
-        .map_err(Error::Git)
+        });
+
+        match result {
+            // libgit2 checks this itself before pushing where it can
+            Err(ref err) if err.code() == git2::ErrorCode::NotFastForward => {
+                return Err(Error::NonFastForward(err.message().to_owned()));
+            }
+            result => result?,
+        }
+
+        match rejected.into_inner() {
+            Some(ref status) if is_non_fast_forward(status) => {
+                Err(Error::NonFastForward(status.to_owned()))
+            }
+            Some(status) => Err(Error::InvalidRef(status)),
+            None => Ok(()),
+        }
+    }
+
+    /// Force pushes the branch only if the ref pushes go to is still at
+    /// `expected` on the remote, so nothing pushed since it was fetched is
+    /// lost. libgit2 can't make a push conditional, so this uses `git`.
+    fn force_push_with_lease(&self, expected: git2::Oid) -> Result<(), Error> {
+        let (name, credentials) = match self.remote {
+            Some(ref remote) => (&remote.name, &remote.credentials),
+            None => return Ok(()),
+        };
+
+        let lease = format!("--force-with-lease={}:{}", self.upstream, expected);
+        let refspec = self.push_refspec.trim_start_matches('+');
+        self.push_with_git(credentials, name, &[&lease, refspec])
+    }
+
+    /// Pushes with the `git` command, as libgit2 can't find what to send
+    /// from a shallow checkout.
+    fn push_with_git(
+        &self,
+        credentials: &GitCredentials,
+        name: &str,
+        args: &[&str],
+    ) -> Result<(), Error> {
+        let mut command = credentials.git_command();
+        command
+            .current_dir(&self.checkout_path)
+            .args(&["push", "--porcelain", name])
+            .args(args);
+
+        // Refs the remote refused are listed with a `!` flag
+        match git(command) {
+            Ok(_) => Ok(()),
+            Err(Error::Git(err)) => {
+                let rejected = err.message().lines().find(|l| l.starts_with('!'));
+                match rejected.map(str::to_owned) {
+                    Some(ref status) if is_non_fast_forward(status) => {
+                        Err(Error::NonFastForward(status.to_owned()))
+                    }
+                    Some(status) => Err(Error::InvalidRef(status)),
+                    None => Err(Error::Git(err)),
+                }
+            }
+            Err(err) => Err(err),
+        }
     }
 
     pub fn reset_head(&self) -> Result<(), Error> {
         debug!("Reseting head");
-        with_authentication(&self.url, &self.repository.config()?, |f| {
-            let mut cb = git2::RemoteCallbacks::new();
-            cb.credentials(f);
+        let target = self.fetch()?;
+        let obj = self.repository.find_object(target, None)?;
+        self.repository.reset(&obj, git2::ResetType::Hard, None)?;
+        Ok(())
+    }
 
-            let mut origin = self.repository.find_remote("origin")?;
+    /// Checks out the branch from the remote, returning whether it exists
+    /// there yet.
+    pub fn checkout_remote_branch(&self) -> Result<bool, Error> {
+        let target = match self.fetch() {
+            Ok(target) => target,
+            Err(Error::Git(ref err)) if err.code() == git2::ErrorCode::NotFound => {
+                return Ok(false)
+            }
+            Err(err) => return Err(err),
+        };
 
-            let mut opts = git2::FetchOptions::new();
-            opts.remote_callbacks(cb);
+        self.set_branch(target, "Checking out the index")?;
+        let obj = self.repository.find_object(target, None)?;
+        self.repository.reset(&obj, git2::ResetType::Hard, None)?;
 
-            origin.fetch(&["refs/heads/*:refs/heads/*"], Some(&mut opts), None)?;
-            let head = self.repository.head()?.target().unwrap();
-            let obj = self.repository.find_object(head, None)?;
-            self.repository.reset(&obj, git2::ResetType::Hard, None)?;
-            Ok(())
-        })
+        Ok(true)
+    }
+
+    /// Fetches the ref pushes go to from the remote, returning the commit
+    /// it's at. Until it has been pushed to, that's where the branch is.
+    fn fetch(&self) -> Result<git2::Oid, Error> {
+        // A hosted index is only ever changed by pallet
+        let (url, name, credentials) = match self.remote {
+            Some(ref remote) => (&remote.url, &remote.name, &remote.credentials),
+            None => {
+                let branch = format!("refs/heads/{}", self.branch);
+                return self.repository.refname_to_id(&branch).map_err(Error::Git);
+            }
+        };
+
+        let branch = format!("refs/heads/{}", self.branch);
+        let tracking_refs = [&self.upstream, &branch]
+            .iter()
+            .map(|refname| {
+                let short = refname.trim_start_matches("refs/heads/");
+                (refname, format!("refs/remotes/{}/{}", name, short))
+            })
+            .collect::<Vec<_>>();
+        let refspecs = tracking_refs
+            .iter()
+            .map(|(refname, tracking_ref)| format!("+{}:{}", refname, tracking_ref))
+            .collect::<Vec<_>>();
+
+        if self.shallow {
+            // One at a time, as git fails the whole fetch if a ref is missing
+            for refspec in &refspecs {
+                let mut command = credentials.git_command();
+                command
+                    .current_dir(&self.checkout_path)
+                    .args(&["fetch", "--depth", "1"])
+                    .arg(name)
+                    .arg(refspec);
+                match git(command) {
+                    Err(Error::Git(ref err))
+                        if err.message().contains("couldn't find remote ref") => {}
+                    result => {
+                        result?;
+                    }
+                }
+            }
+        } else {
+            with_authentication(url, &self.repository.config()?, credentials, |f| {
+                let mut cb = git2::RemoteCallbacks::new();
+                cb.credentials(f);
+
+                let mut remote = self.repository.find_remote(name)?;
+
+                let mut opts = git2::FetchOptions::new();
+                opts.remote_callbacks(cb);
+
+                remote.fetch(&refspecs, Some(&mut opts), None)
+            })?;
+        }
+
+        match self.repository.refname_to_id(&tracking_refs[0].1) {
+            Err(ref err) if err.code() == git2::ErrorCode::NotFound => {
+                self.repository.refname_to_id(&tracking_refs[1].1)
+            }
+            result => result,
+        }
         .map_err(Error::Git)
     }
+
+    /// Replaces the history of the branch with a single root commit of its
+    /// current tree, so clones stay small. The old history is pushed to a
+    /// archive branch named after the date and the commit it's at first if
+    /// `--index-squash-archive` is set.
+    pub fn squash(&self) -> Result<(), Error> {
+        self.reset_head()?;
+
+        let head = self.repository.head()?.peel_to_commit()?;
+        if head.parent_count() == 0 {
+            info!("The index history is already a single commit");
+            return Ok(());
+        }
+
+        if let Some(ref prefix) = self.archive_prefix {
+            // Named after the commit, so squashing twice in a day archives
+            // both histories and retrying a failed squash finds its archive.
+            let archive = format!(
+                "refs/heads/{}{}-{}",
+                prefix,
+                chrono::Utc::now().format("%Y-%m-%d"),
+                &head.id().to_string()[..8]
+            );
+            info!("Archiving the index history to {}", archive);
+            self.repository
+                .reference(&archive, head.id(), true, "Archiving the index history")?;
+            // Not forced, so an archive of other history is never overwritten
+            self.push(&format!("{}:{}", archive, archive))?;
+        }
+
+        let squashed = self.commit(
+            &format!("Squashing the index history up to {}", head.id()),
+            &head.tree()?,
+            &[],
+        )?;
+
+        self.set_branch(squashed, "Squashing the index history")?;
+        let obj = self.repository.find_object(squashed, None)?;
+        self.repository.reset(&obj, git2::ResetType::Hard, None)?;
+
+        // Pushes through pallet wait for the index lock held by the caller,
+        // and anything pushed from elsewhere since the reset fails the lease.
+        info!("Force pushing the squashed index");
+        match self.force_push_with_lease(head.id()) {
+            Err(Error::InvalidRef(ref status)) if status.contains("stale info") => Err(
+                Error::IndexWrite("the index changed while it was being squashed".to_owned()),
+            ),
+            result => result,
+        }
+    }
+}
+
+fn remote(url: &str, opts: &GitOpts) -> Result<Remote, Error> {
+    Ok(Remote {
+        url: url.to_owned(),
+        name: opts.index_remote.to_owned(),
+        credentials: GitCredentials::from_opts(opts)?,
+    })
+}
+
+/// Signs the content of a commit the way git does, returning the armored
+/// signature.
+fn sign(format: SigningFormat, key: &str, content: &[u8]) -> Result<String, Error> {
+    let mut command = match format {
+        SigningFormat::Gpg => {
+            let mut command = Command::new("gpg");
+            command.args(&["--detach-sign", "--armor", "--local-user", key]);
+            command
+        }
+        SigningFormat::Ssh => {
+            let mut command = Command::new("ssh-keygen");
+            command.args(&["-Y", "sign", "-n", "git", "-f", key]);
+            command
+        }
+    };
+
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(content)?;
+    let output = child.wait_with_output()?;
+
+    if !output.status.success() {
+        return Err(Error::Signing(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+
+    String::from_utf8(output.stdout)
+        .map_err(|_| Error::Signing("the signature isn't UTF-8".to_owned()))
+}
+
+/// Runs a `git` command for what libgit2 can't do. Its output is in the
+/// error if it fails.
+fn git(mut command: Command) -> Result<(), Error> {
+    let output = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()?;
+
+    if !output.status.success() {
+        let mut message = String::from_utf8_lossy(&output.stdout).into_owned();
+        message.push_str(&String::from_utf8_lossy(&output.stderr));
+        return Err(Error::Git(git2::Error::from_str(message.trim())));
+    }
+
+    Ok(())
+}
+
+/// The remote ref a push refspec such as `refs/heads/main:refs/heads/index`
+/// updates.
+fn push_destination(refspec: &str) -> String {
+    let refspec = refspec.trim_start_matches('+');
+    let destination = refspec.splitn(2, ':').last().unwrap_or(refspec);
+
+    if destination.starts_with("refs/") {
+        destination.to_owned()
+    } else {
+        format!("refs/heads/{}", destination)
+    }
+}
+
+/// Whether the status of a rejected ref means the remote branch has moved on,
+/// as opposed to e.g. the branch being protected.
+fn is_non_fast_forward(status: &str) -> bool {
+    status.contains("non-fast-forward") || status.contains("fetch first")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opts() -> GitOpts {
+        GitOpts {
+            index_branch: "master".to_owned(),
+            index_remote: "origin".to_owned(),
+            index_push_refspec: None,
+            index_squash_archive: None,
+            index_push_retries: 3,
+            index_shallow: false,
+            index_committer_name: None,
+            index_committer_email: None,
+            index_publish_message: "Updating crate `{name}#{version}`".to_owned(),
+            index_yank_message: "Yanking crate `{name}#{version}`".to_owned(),
+            index_unyank_message: "Unyanking crate `{name}#{version}`".to_owned(),
+            index_signing_key: None,
+            index_signing_format: SigningFormat::Gpg,
+            index_ssh_key: None,
+            index_ssh_key_passphrase: None,
+            index_https_username: None,
+            index_https_token: None,
+            index_https_token_file: None,
+        }
+    }
+
+    fn commit(repo: &git2::Repository, file: &str) {
+        let mut index = repo.index().unwrap();
+        let blob = repo.blob(file.as_bytes()).unwrap();
+        index
+            .add(&git2::IndexEntry {
+                ctime: git2::IndexTime::new(0, 0),
+                mtime: git2::IndexTime::new(0, 0),
+                dev: 0,
+                ino: 0,
+                mode: 0o100_644,
+                uid: 0,
+                gid: 0,
+                file_size: file.len() as u32,
+                id: blob,
+                flags: 0,
+                flags_extended: 0,
+                path: file.as_bytes().to_vec(),
+            })
+            .unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+
+        let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        let parents = parent.iter().collect::<Vec<_>>();
+
+        let sig = git2::Signature::now("pallet", "pallet@localhost").unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, file, &tree, &parents)
+            .unwrap();
+    }
+
+    fn remote(dir: &Path) -> (git2::Repository, String) {
+        let repo = git2::Repository::init_bare(dir).unwrap();
+        repo.set_head("refs/heads/master").unwrap();
+        commit(&repo, "config.json");
+        (repo, dir.to_str().unwrap().to_owned())
+    }
+
+    #[test]
+    fn reuse_checkout_across_restarts() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (upstream, url) = remote(&dir.path().join("upstream"));
+        let checkout = dir.path().join("checkout");
+
+        drop(Repository::open(&url, &checkout, &opts()).unwrap());
+        fs::write(checkout.join("leftover"), "").unwrap();
+
+        // Restarting after another commit was pushed
+        commit(&upstream, "foo");
+        let repository = Repository::open(&url, &checkout, &opts()).unwrap();
+        assert!(checkout.join("foo").exists());
+        assert!(checkout.join("leftover").exists());
+        drop(repository);
+
+        // A checkout of another index is refused and left alone
+        let (_, other) = remote(&dir.path().join("other"));
+        match Repository::open(&other, &checkout, &opts()) {
+            Err(Error::InvalidCheckout(_)) => {}
+            result => panic!(
+                "expected the checkout to be refused, got {:?}",
+                result.err()
+            ),
+        }
+        assert!(checkout.join("foo").exists());
+        assert!(checkout.join("leftover").exists());
+
+        // One git can't read is cloned again
+        fs::remove_dir_all(checkout.join(".git").join("objects")).unwrap();
+        Repository::open(&url, &checkout, &opts()).unwrap();
+        assert!(checkout.join("foo").exists());
+        assert!(!checkout.join("leftover").exists());
+    }
+
+    #[test]
+    fn detect_non_fast_forward_push() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (_, url) = remote(&dir.path().join("upstream"));
+
+        let checkouts = ["a", "b"]
+            .iter()
+            .map(|name| {
+                let repository = Repository::open(&url, &dir.path().join(name), &opts()).unwrap();
+                fs::write(repository.checkout_path().join(name), *name).unwrap();
+                repository
+            })
+            .collect::<Vec<_>>();
+
+        checkouts[0]
+            .commit_and_push("a", &[Path::new("a")])
+            .unwrap();
+        match checkouts[1].commit_and_push("b", &[Path::new("b")]) {
+            Err(Error::NonFastForward(_)) => {}
+            result => panic!("expected a non-fast-forward rejection, got {:?}", result),
+        }
+
+        // Applied again on top of the new head it goes through
+        checkouts[1].reset_head().unwrap();
+        fs::write(checkouts[1].checkout_path().join("b"), "b").unwrap();
+        checkouts[1]
+            .commit_and_push("b", &[Path::new("b")])
+            .unwrap();
+    }
+
+    #[test]
+    fn follow_the_push_destination() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (upstream, url) = remote(&dir.path().join("upstream"));
+        let opts = GitOpts {
+            index_push_refspec: Some("refs/heads/master:refs/heads/index".to_owned()),
+            ..opts()
+        };
+
+        let checkouts = ["a", "b"]
+            .iter()
+            .map(|name| Repository::open(&url, &dir.path().join(name), &opts).unwrap())
+            .collect::<Vec<_>>();
+
+        fs::write(checkouts[0].checkout_path().join("a"), "a").unwrap();
+        checkouts[0]
+            .commit_and_push("a", &[Path::new("a")])
+            .unwrap();
+        assert!(upstream.find_reference("refs/heads/index").is_ok());
+
+        // Changes pushed to `index` are picked up, not those on `master`
+        checkouts[1].reset_head().unwrap();
+        assert!(checkouts[1].checkout_path().join("a").exists());
+        fs::write(checkouts[1].checkout_path().join("b"), "b").unwrap();
+        checkouts[1]
+            .commit_and_push("b", &[Path::new("b")])
+            .unwrap();
+    }
+
+    #[test]
+    fn squash_twice_in_a_day() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (upstream, url) = remote(&dir.path().join("upstream"));
+        let opts = GitOpts {
+            index_squash_archive: Some("snapshot-".to_owned()),
+            ..opts()
+        };
+
+        let repository = Repository::open(&url, &dir.path().join("checkout"), &opts).unwrap();
+
+        for name in &["a", "b"] {
+            fs::write(repository.checkout_path().join(name), *name).unwrap();
+            repository
+                .commit_and_push(name, &[Path::new(name)])
+                .unwrap();
+            repository.squash().unwrap();
+        }
+
+        let head = upstream.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.parent_count(), 0);
+        let archives = upstream
+            .references_glob("refs/heads/snapshot-*")
+            .unwrap()
+            .count();
+        assert_eq!(archives, 2);
+    }
+
+    #[test]
+    fn clone_fetch_and_push_shallow() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (upstream, path) = remote(&dir.path().join("upstream"));
+        commit(&upstream, "foo");
+        // git ignores the depth of clones from a plain path
+        let url = format!("file://{}", path);
+        let opts = GitOpts {
+            index_shallow: true,
+            ..opts()
+        };
+
+        let repository = Repository::open(&url, &dir.path().join("checkout"), &opts).unwrap();
+        assert!(repository.repository.is_shallow());
+        let head = repository.repository.head().unwrap().peel_to_commit();
+        assert!(head.unwrap().parent(0).is_err());
+
+        commit(&upstream, "bar");
+        repository.reset_head().unwrap();
+        assert!(repository.checkout_path().join("bar").exists());
+
+        fs::write(repository.checkout_path().join("a"), "a").unwrap();
+        repository.commit_and_push("a", &[Path::new("a")]).unwrap();
+        let head = upstream.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.message(), Some("a"));
+    }
+
+    #[test]
+    fn parse_push_destination() {
+        assert_eq!(
+            push_destination("refs/heads/main:refs/heads/index"),
+            "refs/heads/index"
+        );
+        assert_eq!(push_destination("+main:index"), "refs/heads/index");
+        assert_eq!(push_destination("refs/heads/main"), "refs/heads/main");
+    }
+
+    #[test]
+    fn commit_as_configured_committer() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let opts = GitOpts {
+            index_committer_name: Some("Registry".to_owned()),
+            index_committer_email: Some("registry@example.com".to_owned()),
+            ..opts()
+        };
+
+        let repository = Repository::host(&dir.path().join("index"), &opts, b"{}").unwrap();
+        let head = repository
+            .repository
+            .head()
+            .unwrap()
+            .peel_to_commit()
+            .unwrap();
+
+        assert_eq!(head.message(), Some("Creating the index"));
+        assert_eq!(head.committer().name(), Some("Registry"));
+        assert_eq!(head.author().email(), Some("registry@example.com"));
+    }
+
+    #[test]
+    fn create_and_use_local_bare_repository() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let url = format!("file://{}", dir.path().join("index.git").display());
+
+        assert!(Repository::create_local_remote(&url, &opts()).unwrap());
+        assert!(!Repository::create_local_remote(&url, &opts()).unwrap());
+
+        let repository = Repository::init(&url, &dir.path().join("init"), &opts()).unwrap();
+        assert!(!repository.checkout_remote_branch().unwrap());
+        fs::write(dir.path().join("init/config.json"), "{}").unwrap();
+        repository
+            .commit_all_and_force_push("Creating the index")
+            .unwrap();
+
+        let checkout = dir.path().join("checkout");
+        Repository::open(&url, &checkout, &opts()).unwrap();
+        assert_eq!(fs::read(checkout.join("config.json")).unwrap(), b"{}");
+    }
 }
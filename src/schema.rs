@@ -37,6 +37,19 @@ table! {
         krate_id -> Int4,
         vers -> Text,
         yanked -> Bool,
+        cksum -> Nullable<Text>,
+        created_at -> Timestamp,
+        metadata -> Nullable<Text>,
+        updated_at -> Timestamp,
+        missing_from_index -> Bool,
+    }
+}
+
+table! {
+    version_downloads (version_id, date) {
+        version_id -> Int4,
+        date -> Date,
+        downloads -> Int4,
     }
 }
 
@@ -44,5 +57,6 @@ joinable!(krateowner -> krate (krate_id));
 joinable!(krateowner -> owner (owner_id));
 joinable!(token -> owner (owner_id));
 joinable!(version -> krate (krate_id));
+joinable!(version_downloads -> version (version_id));
 
-allow_tables_to_appear_in_same_query!(krate, krateowner, owner, token, version,);
+allow_tables_to_appear_in_same_query!(krate, krateowner, owner, token, version, version_downloads,);
@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::commands::LocalOpts;
+use crate::error::Error;
+
+use super::{Local, Object, Storage};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// How well the cache is doing.
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_ratio: f64,
+    pub entries: usize,
+    pub size: u64,
+    pub budget: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    /// Size of each cached tarball and when it was last used.
+    entries: HashMap<(String, String), (u64, u64)>,
+    size: u64,
+    clock: u64,
+}
+
+impl Entries {
+    fn touch(&mut self, key: &(String, String)) -> bool {
+        self.clock += 1;
+        let clock = self.clock;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                entry.1 = clock;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, key: (String, String), size: u64) {
+        self.remove(&key);
+        self.clock += 1;
+        self.entries.insert(key, (size, self.clock));
+        self.size += size;
+    }
+
+    fn remove(&mut self, key: &(String, String)) {
+        if let Some((size, _)) = self.entries.remove(key) {
+            self.size -= size;
+        }
+    }
+
+    /// Picks the least recently used tarballs to evict until the cache fits
+    /// in `budget`.
+    fn evict(&mut self, budget: u64) -> Vec<(String, String)> {
+        let mut evicted = Vec::new();
+        while self.size > budget {
+            let key = match self.entries.iter().min_by_key(|(_, entry)| entry.1) {
+                Some((key, _)) => key.clone(),
+                None => break,
+            };
+            self.remove(&key);
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+/// Keeps recently downloaded tarballs on local disk in front of a slower
+/// backend, evicting the least recently used once `budget` bytes are used.
+///
+/// Tarballs are cached as they're read and as they're published, so new
+/// versions are warm before their first download.
+pub struct Cached {
+    inner: Arc<dyn Storage>,
+    disk: Local,
+    budget: u64,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Cached {
+    /// Opens the cache in `path`, picking up tarballs cached by a previous
+    /// run.
+    pub fn new(inner: Arc<dyn Storage>, path: PathBuf, budget: u64) -> Result<Self, Error> {
+        let disk = Local::new(&LocalOpts {
+            local_base_path: Some(path),
+        })?;
+
+        let mut cached = disk
+            .list()?
+            .into_iter()
+            .map(|(name, version)| {
+                let last_modified = disk.last_modified(&name, &version)?;
+                Ok((last_modified, (name, version)))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        cached.sort();
+
+        let mut entries = Entries::default();
+        for (_, (name, version)) in cached {
+            let length = disk.stream(&name, &version)?.length;
+            entries.insert((name, version), length);
+        }
+
+        let cached = Cached {
+            inner,
+            disk,
+            budget,
+            entries: Mutex::new(entries),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        };
+        cached.evict()?;
+
+        Ok(cached)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let entries = self.entries.lock().unwrap();
+
+        CacheStats {
+            hits,
+            misses,
+            hit_ratio: if hits + misses > 0 {
+                hits as f64 / (hits + misses) as f64
+            } else {
+                0.0
+            },
+            entries: entries.entries.len(),
+            size: entries.size,
+            budget: self.budget,
+        }
+    }
+
+    fn cache(&self, name: &str, version: &str, content: &[u8]) -> Result<(), Error> {
+        if content.len() as u64 > self.budget {
+            return Ok(());
+        }
+
+        self.disk.replace(name, version, content)?;
+        self.entries
+            .lock()
+            .unwrap()
+            .insert((name.to_owned(), version.to_owned()), content.len() as u64);
+
+        self.evict()
+    }
+
+    fn uncache(&self, name: &str, version: &str) -> Result<(), Error> {
+        self.entries
+            .lock()
+            .unwrap()
+            .remove(&(name.to_owned(), version.to_owned()));
+        self.disk.delete(name, version)
+    }
+
+    fn evict(&self) -> Result<(), Error> {
+        let evicted = self.entries.lock().unwrap().evict(self.budget);
+        for (name, version) in evicted {
+            debug!("Evicting {} {} from the cache", name, version);
+            self.disk.delete(&name, &version)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a tarball from the cache, dropping it if it can't be read back
+    /// intact.
+    fn read_cached(&self, name: &str, version: &str) -> Option<Vec<u8>> {
+        let key = (name.to_owned(), version.to_owned());
+        if !self.entries.lock().unwrap().touch(&key) {
+            return None;
+        }
+
+        let mut content = Vec::new();
+        let result = self.disk.stream(name, version).and_then(|mut object| {
+            object.body.read_to_end(&mut content)?;
+            Ok(())
+        });
+
+        match result {
+            Ok(()) => Some(content),
+            Err(err) => {
+                warn!("Dropping {} {} from the cache: {}", name, version, err);
+                if let Err(err) = self.uncache(name, version) {
+                    warn!(
+                        "Failed to remove {} {} from the cache: {}",
+                        name, version, err
+                    );
+                }
+                None
+            }
+        }
+    }
+}
+
+impl Storage for Cached {
+    fn put(&self, name: &str, version: &str, content: &[u8]) -> Result<(), Error> {
+        self.inner.put(name, version, content)?;
+
+        if let Err(err) = self.cache(name, version, content) {
+            warn!("Failed to cache {} {}: {}", name, version, err);
+        }
+
+        Ok(())
+    }
+
+    fn replace(&self, name: &str, version: &str, content: &[u8]) -> Result<(), Error> {
+        // Drop the old copy first so it's never served after the replace
+        self.uncache(name, version)?;
+        self.inner.replace(name, version, content)?;
+
+        if let Err(err) = self.cache(name, version, content) {
+            warn!("Failed to cache {} {}: {}", name, version, err);
+        }
+
+        Ok(())
+    }
+
+    fn get(&self, name: &str, version: &str) -> Result<String, Error> {
+        self.inner.get(name, version)
+    }
+
+    fn delete(&self, name: &str, version: &str) -> Result<(), Error> {
+        self.uncache(name, version)?;
+        self.inner.delete(name, version)
+    }
+
+    fn exists(&self, name: &str, version: &str) -> Result<bool, Error> {
+        self.inner.exists(name, version)
+    }
+
+    fn stream(&self, name: &str, version: &str) -> Result<Object, Error> {
+        let content = match self.read_cached(name, version) {
+            Some(content) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                content
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+
+                let mut content = Vec::new();
+                self.inner
+                    .stream(name, version)?
+                    .body
+                    .read_to_end(&mut content)?;
+
+                if let Err(err) = self.cache(name, version, &content) {
+                    warn!("Failed to cache {} {}: {}", name, version, err);
+                }
+
+                content
+            }
+        };
+
+        Ok(Object {
+            length: content.len() as u64,
+            body: Box::new(Cursor::new(content)),
+        })
+    }
+
+    fn list(&self) -> Result<Vec<(String, String)>, Error> {
+        self.inner.list()
+    }
+
+    fn last_modified(&self, name: &str, version: &str) -> Result<Option<DateTime<Utc>>, Error> {
+        self.inner.last_modified(name, version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::Memory;
+
+    fn read(storage: &dyn Storage, name: &str, version: &str) -> Vec<u8> {
+        let mut content = Vec::new();
+        storage
+            .stream(name, version)
+            .unwrap()
+            .body
+            .read_to_end(&mut content)
+            .unwrap();
+        content
+    }
+
+    #[test]
+    fn serve_hits_from_disk_and_evict_least_recently_used() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let inner = Arc::new(Memory::default());
+        inner.put("foo", "1.0.0", b"foo").unwrap();
+        inner.put("bar", "1.0.0", b"bar").unwrap();
+
+        let cached = Cached::new(inner.clone(), dir.path().to_path_buf(), 6).unwrap();
+
+        assert_eq!(read(&cached, "foo", "1.0.0"), b"foo");
+        assert_eq!(read(&cached, "bar", "1.0.0"), b"bar");
+
+        inner.set_unavailable(true);
+        assert_eq!(read(&cached, "foo", "1.0.0"), b"foo");
+        inner.set_unavailable(false);
+
+        // Warmed on publish, pushing out `bar` as `foo` was used more recently
+        cached.put("baz", "1.0.0", b"baz").unwrap();
+
+        let stats = cached.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 2);
+        assert_eq!(stats.entries, 2);
+        assert_eq!(stats.size, 6);
+
+        inner.set_unavailable(true);
+        assert_eq!(read(&cached, "baz", "1.0.0"), b"baz");
+        assert!(cached.stream("bar", "1.0.0").is_err());
+        inner.set_unavailable(false);
+
+        // Picked up again after a restart
+        let reopened = Cached::new(inner, dir.path().to_path_buf(), 6).unwrap();
+        assert_eq!(reopened.stats().entries, 2);
+    }
+}
@@ -0,0 +1,464 @@
+use std::fmt;
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::commands::EncryptionOpts;
+use crate::error::Error;
+
+use super::{Object, Storage};
+
+use aes_gcm::aead::{generic_array::GenericArray, Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+// Layout of an encrypted tarball:
+//
+//   MAGIC | key id | key nonce | wrapped data key | data nonce | ciphertext
+const MAGIC: &[u8] = b"PALLET\x00\x01";
+const KEY_LEN: usize = 32;
+const KEY_ID_LEN: usize = 8;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const WRAPPED_KEY_LEN: usize = KEY_LEN + TAG_LEN;
+const HEADER_LEN: usize = MAGIC.len() + KEY_ID_LEN + NONCE_LEN + WRAPPED_KEY_LEN + NONCE_LEN;
+
+/// The key that every per-tarball data key is wrapped with.
+pub struct MasterKey {
+    id: [u8; KEY_ID_LEN],
+    cipher: Aes256Gcm,
+}
+
+impl MasterKey {
+    pub fn new(key: &[u8]) -> Result<Self, Error> {
+        if key.len() != KEY_LEN {
+            return Err(Error::InvalidEncryptionKey(format!(
+                "expected {} bytes, found {}",
+                KEY_LEN,
+                key.len()
+            )));
+        }
+
+        // Identifies which master key a data key was wrapped with, without
+        // revealing anything about the key.
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&Sha256::digest(key)[..KEY_ID_LEN]);
+
+        Ok(MasterKey {
+            id,
+            cipher: Aes256Gcm::new(GenericArray::from_slice(key)),
+        })
+    }
+
+    /// Parses a hex encoded 256-bit key.
+    pub fn from_hex(key: &str) -> Result<Self, Error> {
+        let key =
+            hex::decode(key.trim()).map_err(|err| Error::InvalidEncryptionKey(err.to_string()))?;
+        MasterKey::new(&key)
+    }
+
+    /// Loads the master key from `--encryption-key` or
+    /// `--encryption-key-file`, if either is set.
+    pub fn from_opts(opts: &EncryptionOpts) -> Result<Option<Self>, Error> {
+        MasterKey::load(&opts.encryption_key, &opts.encryption_key_file)
+    }
+
+    /// Loads the decrypt-only key from `--old-encryption-key` or
+    /// `--old-encryption-key-file`, if either is set.
+    pub fn old_from_opts(opts: &EncryptionOpts) -> Result<Option<Self>, Error> {
+        MasterKey::load(&opts.old_encryption_key, &opts.old_encryption_key_file)
+    }
+
+    fn load(key: &Option<String>, key_file: &Option<PathBuf>) -> Result<Option<Self>, Error> {
+        match (key, key_file) {
+            (Some(key), _) => Ok(Some(MasterKey::from_hex(key)?)),
+            (None, Some(path)) => Ok(Some(MasterKey::from_hex(&fs::read_to_string(path)?)?)),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Whether a tarball is encrypted, and under which master key.
+#[derive(Debug, PartialEq)]
+pub enum Envelope {
+    Plaintext,
+    Encrypted([u8; KEY_ID_LEN]),
+}
+
+impl Envelope {
+    pub fn of(content: &[u8]) -> Self {
+        if content.len() >= HEADER_LEN + TAG_LEN && content.starts_with(MAGIC) {
+            let mut id = [0; KEY_ID_LEN];
+            id.copy_from_slice(&content[MAGIC.len()..MAGIC.len() + KEY_ID_LEN]);
+            Envelope::Encrypted(id)
+        } else {
+            Envelope::Plaintext
+        }
+    }
+
+    pub fn is_wrapped_by(&self, key: &MasterKey) -> bool {
+        *self == Envelope::Encrypted(key.id)
+    }
+}
+
+/// Encrypts a tarball under a fresh data key, wrapped by `key`.
+pub fn encrypt(key: &MasterKey, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut data_key = [0; KEY_LEN];
+    rand::thread_rng().fill_bytes(&mut data_key);
+
+    let mut data_nonce = [0; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut data_nonce);
+
+    let ciphertext = Aes256Gcm::new(GenericArray::from_slice(&data_key))
+        .encrypt(GenericArray::from_slice(&data_nonce), plaintext)
+        .map_err(|_| Error::Encryption)?;
+
+    let mut content = wrap(key, &data_key)?;
+    content.extend_from_slice(&data_nonce);
+    content.extend_from_slice(&ciphertext);
+
+    Ok(content)
+}
+
+/// Decrypts a tarball, returning it unchanged if it isn't encrypted.
+pub fn decrypt(key: &MasterKey, content: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if Envelope::of(&content) == Envelope::Plaintext {
+        return Ok(content);
+    }
+
+    let data_key = unwrap(key, &content)?;
+    let data_nonce = &content[HEADER_LEN - NONCE_LEN..HEADER_LEN];
+
+    Aes256Gcm::new(GenericArray::from_slice(&data_key))
+        .decrypt(GenericArray::from_slice(data_nonce), &content[HEADER_LEN..])
+        .map_err(|_| Error::Decryption)
+}
+
+/// Re-wraps the data key of an encrypted tarball under a new master key,
+/// leaving the ciphertext untouched.
+pub fn rewrap(old: &MasterKey, new: &MasterKey, content: &[u8]) -> Result<Vec<u8>, Error> {
+    let data_key = unwrap(old, content)?;
+
+    let mut rewrapped = wrap(new, &data_key)?;
+    rewrapped.extend_from_slice(&content[HEADER_LEN - NONCE_LEN..]);
+
+    Ok(rewrapped)
+}
+
+fn wrap(key: &MasterKey, data_key: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut key_nonce = [0; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut key_nonce);
+
+    let wrapped_key = key
+        .cipher
+        .encrypt(GenericArray::from_slice(&key_nonce), data_key)
+        .map_err(|_| Error::Encryption)?;
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&key.id);
+    header.extend_from_slice(&key_nonce);
+    header.extend_from_slice(&wrapped_key);
+
+    Ok(header)
+}
+
+fn unwrap(key: &MasterKey, content: &[u8]) -> Result<Vec<u8>, Error> {
+    match Envelope::of(content) {
+        Envelope::Encrypted(id) if id == key.id => {}
+        Envelope::Encrypted(id) => return Err(Error::UnknownEncryptionKey(hex::encode(id))),
+        Envelope::Plaintext => return Err(Error::Decryption),
+    }
+
+    let key_nonce_start = MAGIC.len() + KEY_ID_LEN;
+    let wrapped_key_start = key_nonce_start + NONCE_LEN;
+
+    key.cipher
+        .decrypt(
+            GenericArray::from_slice(&content[key_nonce_start..wrapped_key_start]),
+            &content[wrapped_key_start..wrapped_key_start + WRAPPED_KEY_LEN],
+        )
+        .map_err(|_| Error::Decryption)
+}
+
+/// The outcome of re-wrapping every data key under a new master key.
+#[derive(Debug, Default)]
+pub struct Rotation {
+    pub rewrapped: usize,
+    pub encrypted: usize,
+    pub skipped: usize,
+    pub failed: Vec<(String, String, String)>,
+}
+
+impl fmt::Display for Rotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, version, reason) in &self.failed {
+            writeln!(f, "failed: {} {}: {}", name, version, reason)?;
+        }
+        writeln!(
+            f,
+            "{} re-wrapped, {} encrypted, {} already using the new key, {} failed",
+            self.rewrapped,
+            self.encrypted,
+            self.skipped,
+            self.failed.len()
+        )
+    }
+}
+
+/// Re-wraps the data key of every tarball in `storage` under `new`, unwrapping
+/// it with whichever of the `old` keys it was wrapped by.
+///
+/// Tarballs stored before encryption was enabled are encrypted, and those
+/// already using `new` are skipped so an interrupted rotation can be resumed.
+pub fn rotate(
+    storage: &dyn Storage,
+    old: &[MasterKey],
+    new: &MasterKey,
+) -> Result<Rotation, Error> {
+    let mut rotation = Rotation::default();
+
+    for (name, version) in storage.list()? {
+        match rotate_tarball(storage, &name, &version, old, new) {
+            Ok(Rotated::Rewrapped) => rotation.rewrapped += 1,
+            Ok(Rotated::Encrypted) => rotation.encrypted += 1,
+            Ok(Rotated::Skipped) => rotation.skipped += 1,
+            Err(err) => {
+                warn!("Failed to rotate the key of {} {}: {}", name, version, err);
+                rotation.failed.push((name, version, err.to_string()));
+            }
+        }
+    }
+
+    Ok(rotation)
+}
+
+enum Rotated {
+    Rewrapped,
+    Encrypted,
+    Skipped,
+}
+
+fn rotate_tarball(
+    storage: &dyn Storage,
+    name: &str,
+    version: &str,
+    old: &[MasterKey],
+    new: &MasterKey,
+) -> Result<Rotated, Error> {
+    let mut content = Vec::new();
+    storage
+        .stream(name, version)?
+        .body
+        .read_to_end(&mut content)?;
+
+    match Envelope::of(&content) {
+        ref envelope if envelope.is_wrapped_by(new) => Ok(Rotated::Skipped),
+        Envelope::Plaintext => {
+            storage.replace(name, version, &encrypt(new, &content)?)?;
+            Ok(Rotated::Encrypted)
+        }
+        Envelope::Encrypted(id) => match old.iter().find(|old| old.id == id) {
+            Some(old) => {
+                storage.replace(name, version, &rewrap(old, new, &content)?)?;
+                Ok(Rotated::Rewrapped)
+            }
+            None => Err(Error::UnknownEncryptionKey(hex::encode(id))),
+        },
+    }
+}
+
+/// Encrypts tarballs before they're written to another backend, and
+/// decrypts them as they're read back.
+///
+/// Tarballs stored before encryption was enabled are read as they are, and
+/// those wrapped by the `old` key while a rotation is underway are decrypted
+/// with it.
+pub struct Encrypted {
+    inner: Arc<dyn Storage>,
+    key: MasterKey,
+    old: Option<MasterKey>,
+}
+
+impl Encrypted {
+    pub fn new(inner: Arc<dyn Storage>, key: MasterKey, old: Option<MasterKey>) -> Self {
+        Encrypted { inner, key, old }
+    }
+
+    /// The key a tarball was wrapped by, as recorded in its header.
+    fn key_for(&self, content: &[u8]) -> &MasterKey {
+        match self.old {
+            Some(ref old) if Envelope::of(content).is_wrapped_by(old) => old,
+            _ => &self.key,
+        }
+    }
+
+    fn read(&self, name: &str, version: &str) -> Result<Vec<u8>, Error> {
+        let mut content = Vec::new();
+        self.inner
+            .stream(name, version)?
+            .body
+            .read_to_end(&mut content)?;
+
+        decrypt(self.key_for(&content), content)
+    }
+}
+
+impl Storage for Encrypted {
+    fn put(&self, name: &str, version: &str, content: &[u8]) -> Result<(), Error> {
+        match self.inner.put(name, version, &encrypt(&self.key, content)?) {
+            // Every encryption is different, so re-uploading the same
+            // tarball is only noticed once it's decrypted.
+            Err(Error::ObjectExists(path)) => {
+                if self.read(name, version)? == content {
+                    Ok(())
+                } else {
+                    Err(Error::ObjectExists(path))
+                }
+            }
+            result => result,
+        }
+    }
+
+    fn replace(&self, name: &str, version: &str, content: &[u8]) -> Result<(), Error> {
+        self.inner
+            .replace(name, version, &encrypt(&self.key, content)?)
+    }
+
+    fn get(&self, _name: &str, _version: &str) -> Result<String, Error> {
+        // Redirecting to the backend would hand out the ciphertext
+        Err(Error::EncryptionRequiresProxy)
+    }
+
+    fn delete(&self, name: &str, version: &str) -> Result<(), Error> {
+        self.inner.delete(name, version)
+    }
+
+    fn exists(&self, name: &str, version: &str) -> Result<bool, Error> {
+        self.inner.exists(name, version)
+    }
+
+    fn stream(&self, name: &str, version: &str) -> Result<Object, Error> {
+        let plaintext = self.read(name, version)?;
+
+        Ok(Object {
+            length: plaintext.len() as u64,
+            body: Box::new(Cursor::new(plaintext)),
+        })
+    }
+
+    fn list(&self) -> Result<Vec<(String, String)>, Error> {
+        self.inner.list()
+    }
+
+    fn last_modified(&self, name: &str, version: &str) -> Result<Option<DateTime<Utc>>, Error> {
+        self.inner.last_modified(name, version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::Memory;
+
+    fn read(storage: &dyn Storage, name: &str, version: &str) -> Vec<u8> {
+        let mut content = Vec::new();
+        storage
+            .stream(name, version)
+            .unwrap()
+            .body
+            .read_to_end(&mut content)
+            .unwrap();
+        content
+    }
+
+    #[test]
+    fn encrypt_and_decrypt() {
+        let inner = Arc::new(Memory::default());
+        let encrypted = Encrypted::new(inner.clone(), MasterKey::new(&[1; KEY_LEN]).unwrap(), None);
+
+        encrypted.put("foo", "1.0.0", b"foo").unwrap();
+        inner.put("bar", "1.0.0", b"bar").unwrap();
+
+        assert!(Envelope::of(&read(&*inner, "foo", "1.0.0")).is_wrapped_by(&encrypted.key));
+        assert_eq!(read(&encrypted, "foo", "1.0.0"), b"foo");
+        assert_eq!(read(&encrypted, "bar", "1.0.0"), b"bar");
+
+        // Re-publishing the same tarball is fine, replacing it isn't
+        encrypted.put("foo", "1.0.0", b"foo").unwrap();
+        assert!(encrypted.put("foo", "1.0.0", b"bar").is_err());
+        assert_eq!(read(&encrypted, "foo", "1.0.0"), b"foo");
+    }
+
+    #[test]
+    fn decrypt_with_old_key() {
+        let old = MasterKey::new(&[1; KEY_LEN]).unwrap();
+        let new = MasterKey::new(&[2; KEY_LEN]).unwrap();
+
+        let inner = Arc::new(Memory::default());
+        inner
+            .put("foo", "1.0.0", &encrypt(&old, b"foo").unwrap())
+            .unwrap();
+
+        let encrypted = Encrypted::new(inner, new, Some(old));
+        encrypted.put("bar", "1.0.0", b"bar").unwrap();
+
+        assert_eq!(read(&encrypted, "foo", "1.0.0"), b"foo");
+        assert_eq!(read(&encrypted, "bar", "1.0.0"), b"bar");
+    }
+
+    #[test]
+    fn rewrap_under_new_key() {
+        let old = MasterKey::new(&[1; KEY_LEN]).unwrap();
+        let new = MasterKey::new(&[2; KEY_LEN]).unwrap();
+
+        let content = encrypt(&old, b"foo").unwrap();
+        let rewrapped = rewrap(&old, &new, &content).unwrap();
+
+        assert!(Envelope::of(&rewrapped).is_wrapped_by(&new));
+        assert_eq!(content[HEADER_LEN..], rewrapped[HEADER_LEN..]);
+        assert_eq!(decrypt(&new, rewrapped.clone()).unwrap(), b"foo");
+        assert!(decrypt(&old, rewrapped).is_err());
+    }
+
+    #[test]
+    fn rotate_every_tarball() {
+        let old = MasterKey::new(&[1; KEY_LEN]).unwrap();
+        let new = MasterKey::new(&[2; KEY_LEN]).unwrap();
+        let unknown = MasterKey::new(&[3; KEY_LEN]).unwrap();
+
+        let storage = Memory::default();
+        storage
+            .put("foo", "1.0.0", &encrypt(&old, b"foo").unwrap())
+            .unwrap();
+        storage.put("bar", "1.0.0", b"bar").unwrap();
+        storage
+            .put("baz", "1.0.0", &encrypt(&new, b"baz").unwrap())
+            .unwrap();
+        storage
+            .put("qux", "1.0.0", &encrypt(&unknown, b"qux").unwrap())
+            .unwrap();
+
+        let rotation = rotate(&storage, &[old], &new).unwrap();
+
+        assert_eq!(rotation.rewrapped, 1);
+        assert_eq!(rotation.encrypted, 1);
+        assert_eq!(rotation.skipped, 1);
+        assert_eq!(rotation.failed.len(), 1);
+        assert_eq!(rotation.failed[0].0, "qux");
+
+        let encrypted = Encrypted::new(Arc::new(storage), new, None);
+        assert_eq!(read(&encrypted, "foo", "1.0.0"), b"foo");
+        assert_eq!(read(&encrypted, "bar", "1.0.0"), b"bar");
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert!(MasterKey::new(&[1; 16]).is_err());
+        assert!(MasterKey::from_hex("not hex").is_err());
+        assert!(MasterKey::from_hex(&"01".repeat(KEY_LEN)).is_ok());
+    }
+}
@@ -1,43 +1,334 @@
 use std::fs::{self, File};
-use std::io::Write;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 
-use crate::commands::Server;
+use crate::commands::LocalOpts;
 use crate::error::Error;
 
+use super::{Corrupted, Object, Storage};
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+
 #[derive(Clone)]
 pub struct Local {
     base_path: PathBuf,
 }
 
 impl Local {
-    pub fn new(server: &Server) -> Self {
-        Local {
-            base_path: server.local_opts.local_base_path.to_path_buf(),
-        }
-    }
+    pub fn new(opts: &LocalOpts) -> Result<Self, Error> {
+        let base_path = opts
+            .local_base_path
+            .as_ref()
+            .ok_or(Error::MissingStorageOption("local-base-path"))?;
 
-    pub fn base_path(&self) -> PathBuf {
-        self.base_path.to_path_buf()
+        Ok(Local {
+            base_path: base_path.to_path_buf(),
+        })
     }
 
-    pub fn put(&self, name: &str, version: &str, content: &[u8]) -> Result<(), Error> {
+    fn filename(&self, name: &str, version: &str) -> PathBuf {
         let crate_path = super::crate_path(name, version);
 
-        let filename = self.base_path.join(Path::new(&crate_path));
+        self.base_path.join(Path::new(&crate_path))
+    }
+
+    /// The file the checksum of a tarball is recorded in when it's stored.
+    fn checksum_filename(&self, name: &str, version: &str) -> PathBuf {
+        let mut filename = self.filename(name, version).into_os_string();
+        filename.push(".sha256");
+        PathBuf::from(filename)
+    }
+
+    fn write_checksum(&self, name: &str, version: &str, content: &[u8]) -> Result<(), Error> {
+        let checksum = format!("{:x}", Sha256::digest(content));
+        write_atomic(
+            &self.checksum_filename(name, version),
+            checksum.as_bytes(),
+            true,
+        )?;
+        Ok(())
+    }
+}
+
+/// Writes a file by renaming a fully written and synced temporary file into
+/// place, so a crash never leaves a partially written file behind.
+fn write_atomic(filename: &Path, content: &[u8], overwrite: bool) -> io::Result<()> {
+    let dir = filename.parent().unwrap();
+
+    let mut file = NamedTempFile::new_in(dir)?;
+    file.write_all(content)?;
+    file.as_file().sync_all()?;
+
+    if overwrite {
+        file.persist(filename).map_err(|err| err.error)?;
+    } else {
+        file.persist_noclobber(filename).map_err(|err| err.error)?;
+    }
+
+    // Make sure the rename itself is durable
+    File::open(dir)?.sync_all()
+}
+
+impl Storage for Local {
+    fn put(&self, name: &str, version: &str, content: &[u8]) -> Result<(), Error> {
+        let filename = self.filename(name, version);
 
         let dir = filename.parent().unwrap();
         fs::create_dir_all(dir)?;
 
-        let mut file = File::create(&filename)?;
-        file.write_all(content)?;
+        let identical = || -> Result<(), Error> {
+            if fs::read(&filename)? == content {
+                Ok(())
+            } else {
+                Err(Error::ObjectExists(super::crate_path(name, version)))
+            }
+        };
 
-        Ok(())
+        // Re-uploading the same tarball is fine, replacing it isn't
+        if filename.exists() {
+            return identical();
+        }
+
+        // The tarball goes first, so the checksum only ever describes the
+        // tarball in place. A put that loses the race leaves it alone.
+        match write_atomic(&filename, content, false) {
+            Ok(()) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => return identical(),
+            Err(err) => return Err(Error::IO(err)),
+        }
+
+        self.write_checksum(name, version, content)
     }
 
-    pub fn get(&self, name: &str, version: &str) -> Result<String, Error> {
+    fn replace(&self, name: &str, version: &str, content: &[u8]) -> Result<(), Error> {
+        let filename = self.filename(name, version);
+
+        let dir = filename.parent().unwrap();
+        fs::create_dir_all(dir)?;
+
+        // Without the old checksum, a crash before the new one is written
+        // leaves the tarball unverified rather than failing every read.
+        match fs::remove_file(self.checksum_filename(name, version)) {
+            Ok(()) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(Error::IO(err)),
+        }
+        write_atomic(&filename, content, true)?;
+
+        self.write_checksum(name, version, content)
+    }
+
+    fn get(&self, name: &str, version: &str) -> Result<String, Error> {
+        // Served by pallet itself from `/local`
         let crate_path = super::crate_path(name, version);
 
-        Ok(crate_path)
+        Ok(format!("/local/{}", crate_path))
+    }
+
+    fn delete(&self, name: &str, version: &str) -> Result<(), Error> {
+        for filename in &[
+            self.filename(name, version),
+            self.checksum_filename(name, version),
+        ] {
+            match fs::remove_file(filename) {
+                Ok(()) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(Error::IO(err)),
+            }
+        }
+        Ok(())
+    }
+
+    fn exists(&self, name: &str, version: &str) -> Result<bool, Error> {
+        Ok(self.filename(name, version).is_file())
+    }
+
+    fn stream(&self, name: &str, version: &str) -> Result<Object, Error> {
+        let file = File::open(self.filename(name, version))?;
+        let length = file.metadata()?.len();
+
+        // Tarballs stored before checksums were recorded can't be verified
+        let body: Box<dyn Read + Send> =
+            match fs::read_to_string(self.checksum_filename(name, version)) {
+                Ok(expected) => Box::new(Verified {
+                    reader: file,
+                    hasher: Sha256::new(),
+                    expected: expected.trim().to_owned(),
+                    remaining: length,
+                    verified: false,
+                }),
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => Box::new(file),
+                Err(err) => return Err(Error::IO(err)),
+            };
+
+        Ok(Object { length, body })
+    }
+
+    fn list(&self) -> Result<Vec<(String, String)>, Error> {
+        let crates_dir = self.base_path.join("crates");
+        if !crates_dir.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut crates = Vec::new();
+        for dir in fs::read_dir(crates_dir)? {
+            let dir = dir?;
+            if !dir.file_type()?.is_dir() {
+                continue;
+            }
+
+            for file in fs::read_dir(dir.path())? {
+                let path = file?.path();
+                let relative_path = path.strip_prefix(&self.base_path).unwrap();
+                let crate_path = relative_path
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+
+                if let Some(krate) = super::parse_crate_path(&crate_path) {
+                    crates.push(krate);
+                }
+            }
+        }
+
+        Ok(crates)
+    }
+
+    fn last_modified(&self, name: &str, version: &str) -> Result<Option<DateTime<Utc>>, Error> {
+        match fs::metadata(self.filename(name, version)) {
+            Ok(metadata) => Ok(Some(metadata.modified()?.into())),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Error::IO(err)),
+        }
+    }
+
+    fn base_path(&self) -> Option<&Path> {
+        Some(&self.base_path)
+    }
+}
+
+/// Hashes a tarball as it's read, failing the read of its last byte if it
+/// doesn't match the checksum recorded when it was stored. Readers stop at
+/// the length of the tarball, so this doesn't wait for EOF.
+struct Verified<R> {
+    reader: R,
+    hasher: Sha256,
+    expected: String,
+    remaining: u64,
+    verified: bool,
+}
+
+impl<R: Read> Read for Verified<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+
+        self.hasher.input(&buf[..n]);
+        self.remaining = self.remaining.saturating_sub(n as u64);
+
+        let finished = self.remaining == 0 || (n == 0 && !buf.is_empty());
+        if finished && !self.verified {
+            self.verified = true;
+
+            let actual = format!("{:x}", self.hasher.clone().result());
+            if actual != self.expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    Corrupted {
+                        actual,
+                        recorded: self.expected.to_owned(),
+                    },
+                ));
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local() -> (tempfile::TempDir, Local) {
+        let dir = tempfile::TempDir::new().unwrap();
+        let local = Local::new(&LocalOpts {
+            local_base_path: Some(dir.path().to_path_buf()),
+        })
+        .unwrap();
+        (dir, local)
+    }
+
+    fn read(local: &Local, name: &str, version: &str) -> io::Result<Vec<u8>> {
+        let mut content = Vec::new();
+        local
+            .stream(name, version)
+            .unwrap()
+            .body
+            .read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    #[test]
+    fn put_refuses_to_overwrite() {
+        let (_dir, local) = local();
+
+        local.put("foo", "1.0.0", b"foo").unwrap();
+        local.put("foo", "1.0.0", b"foo").unwrap();
+        assert!(local.put("foo", "1.0.0", b"bar").is_err());
+
+        assert_eq!(read(&local, "foo", "1.0.0").unwrap(), b"foo");
+        assert_eq!(
+            local.list().unwrap(),
+            vec![("foo".to_owned(), "1.0.0".to_owned())]
+        );
+    }
+
+    #[test]
+    fn stream_verifies_checksum() {
+        let (_dir, local) = local();
+
+        local.put("foo", "1.0.0", b"foo").unwrap();
+        fs::write(local.filename("foo", "1.0.0"), b"bar").unwrap();
+
+        let err = read(&local, "foo", "1.0.0").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Reading exactly the length of the tarball, as downloads do
+        let mut object = local.stream("foo", "1.0.0").unwrap();
+        let mut buf = [0; 3];
+        assert!(object.body.read_exact(&mut buf).is_err());
+
+        assert!(crate::storage::checksum(&local, "foo", "1.0.0").is_err());
+    }
+
+    #[test]
+    fn replace_rewrites_checksum() {
+        let (_dir, local) = local();
+
+        local.put("foo", "1.0.0", b"foo").unwrap();
+        local.replace("foo", "1.0.0", b"bar").unwrap();
+
+        assert_eq!(read(&local, "foo", "1.0.0").unwrap(), b"bar");
+        assert_eq!(
+            fs::read_to_string(local.checksum_filename("foo", "1.0.0")).unwrap(),
+            format!("{:x}", Sha256::digest(b"bar"))
+        );
+    }
+
+    #[test]
+    fn delete_removes_checksum() {
+        let (_dir, local) = local();
+
+        local.put("foo", "1.0.0", b"foo").unwrap();
+        local.delete("foo", "1.0.0").unwrap();
+
+        assert!(!local.exists("foo", "1.0.0").unwrap());
+        assert!(!local.checksum_filename("foo", "1.0.0").exists());
+
+        local.put("foo", "1.0.0", b"bar").unwrap();
+        assert_eq!(read(&local, "foo", "1.0.0").unwrap(), b"bar");
     }
 }
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::io::{self, Cursor};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::error::Error;
+
+use super::{Object, Storage};
+
+use chrono::{DateTime, Utc};
+
+/// Keeps tarballs in memory, for testing code built on top of `Storage`.
+#[derive(Default)]
+pub struct Memory {
+    objects: Mutex<HashMap<(String, String), (Vec<u8>, DateTime<Utc>)>>,
+    unavailable: AtomicBool,
+}
+
+impl Memory {
+    /// Changes when a tarball was last written.
+    pub fn set_last_modified(&self, name: &str, version: &str, last_modified: DateTime<Utc>) {
+        if let Some(object) = self
+            .objects
+            .lock()
+            .unwrap()
+            .get_mut(&(name.to_owned(), version.to_owned()))
+        {
+            object.1 = last_modified;
+        }
+    }
+
+    /// Makes every operation fail, as if the backend couldn't be reached.
+    pub fn set_unavailable(&self, unavailable: bool) {
+        self.unavailable.store(unavailable, Ordering::SeqCst);
+    }
+
+    fn check_available(&self) -> Result<(), Error> {
+        if self.unavailable.load(Ordering::SeqCst) {
+            return Err(Error::IO(io::Error::new(
+                io::ErrorKind::Other,
+                "storage unavailable",
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Storage for Memory {
+    fn put(&self, name: &str, version: &str, content: &[u8]) -> Result<(), Error> {
+        self.check_available()?;
+        self.objects.lock().unwrap().insert(
+            (name.to_owned(), version.to_owned()),
+            (content.to_vec(), Utc::now()),
+        );
+        Ok(())
+    }
+
+    fn replace(&self, name: &str, version: &str, content: &[u8]) -> Result<(), Error> {
+        self.put(name, version, content)
+    }
+
+    fn get(&self, name: &str, version: &str) -> Result<String, Error> {
+        self.check_available()?;
+        Ok(super::crate_path(name, version))
+    }
+
+    fn delete(&self, name: &str, version: &str) -> Result<(), Error> {
+        self.check_available()?;
+        self.objects
+            .lock()
+            .unwrap()
+            .remove(&(name.to_owned(), version.to_owned()));
+        Ok(())
+    }
+
+    fn exists(&self, name: &str, version: &str) -> Result<bool, Error> {
+        self.check_available()?;
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .contains_key(&(name.to_owned(), version.to_owned())))
+    }
+
+    fn stream(&self, name: &str, version: &str) -> Result<Object, Error> {
+        self.check_available()?;
+        let content = self
+            .objects
+            .lock()
+            .unwrap()
+            .get(&(name.to_owned(), version.to_owned()))
+            .map(|object| object.0.clone())
+            .ok_or_else(|| Error::ObjectNotFound(super::crate_path(name, version)))?;
+
+        Ok(Object {
+            length: content.len() as u64,
+            body: Box::new(Cursor::new(content)),
+        })
+    }
+
+    fn list(&self) -> Result<Vec<(String, String)>, Error> {
+        self.check_available()?;
+        Ok(self.objects.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn last_modified(&self, name: &str, version: &str) -> Result<Option<DateTime<Utc>>, Error> {
+        self.check_available()?;
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .get(&(name.to_owned(), version.to_owned()))
+            .map(|object| object.1))
+    }
+}
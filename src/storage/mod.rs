@@ -1,13 +1,330 @@
-#[cfg(feature = "local")]
-pub mod local;
-#[cfg(feature = "s3")]
-pub mod s3;
+mod cached;
+pub mod encrypted;
+mod local;
+#[cfg(test)]
+pub(crate) mod memory;
+pub mod replicated;
+mod s3;
 
-#[cfg(feature = "local")]
-pub type Storage = local::Local;
-#[cfg(feature = "s3")]
-pub type Storage = s3::S3;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::{error, fmt};
+
+use crate::commands::{EncryptionOpts, LocalOpts, S3Opts, StorageOpts};
+use crate::error::Error;
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+pub use cached::{CacheStats, Cached};
+pub use encrypted::{Encrypted, MasterKey};
+pub use local::Local;
+pub use replicated::Replicated;
+pub use s3::S3;
+
+/// A backend that crate tarballs are stored in and served from.
+pub trait Storage: Send + Sync {
+    /// Stores the tarball for a crate version.
+    fn put(&self, name: &str, version: &str, content: &[u8]) -> Result<(), Error>;
+
+    /// Stores the tarball for a crate version, replacing any existing one.
+    fn replace(&self, name: &str, version: &str, content: &[u8]) -> Result<(), Error>;
+
+    /// Returns the URL a client should be redirected to in order to download
+    /// a crate version.
+    fn get(&self, name: &str, version: &str) -> Result<String, Error>;
+
+    /// Removes the tarball for a crate version, succeeding if it doesn't exist.
+    fn delete(&self, name: &str, version: &str) -> Result<(), Error>;
+
+    /// Checks whether a tarball for a crate version is stored.
+    fn exists(&self, name: &str, version: &str) -> Result<bool, Error>;
+
+    /// Opens the tarball for a crate version for reading.
+    fn stream(&self, name: &str, version: &str) -> Result<Object, Error>;
+
+    /// Lists the name and version of every stored tarball.
+    fn list(&self) -> Result<Vec<(String, String)>, Error>;
+
+    /// When the tarball for a crate version was last written, if known.
+    fn last_modified(&self, name: &str, version: &str) -> Result<Option<DateTime<Utc>>, Error>;
+
+    /// Directory the tarballs are stored in, if the backend keeps them on the
+    /// local filesystem.
+    fn base_path(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// A stored tarball opened for reading.
+pub struct Object {
+    pub length: u64,
+    pub body: Box<dyn Read + Send>,
+}
+
+/// The error a tarball that doesn't match the checksum recorded when it was
+/// stored fails to read with, as the payload of an `InvalidData` error.
+#[derive(Debug)]
+pub struct Corrupted {
+    pub actual: String,
+    pub recorded: String,
+}
+
+impl fmt::Display for Corrupted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "checksum {} doesn't match the recorded checksum {}",
+            self.actual, self.recorded
+        )
+    }
+}
+
+impl error::Error for Corrupted {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StorageKind {
+    Local,
+    S3,
+}
+
+impl FromStr for StorageKind {
+    type Err = Error;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            "local" => Ok(StorageKind::Local),
+            "s3" => Ok(StorageKind::S3),
+            _ => Err(Error::UnknownStorage(kind.to_owned())),
+        }
+    }
+}
+
+/// How crate downloads are served to clients.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DownloadMode {
+    /// Redirect the client to the URL returned by `Storage::get`.
+    Redirect,
+    /// Stream the tarball from storage through pallet.
+    Proxy,
+}
+
+impl FromStr for DownloadMode {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "redirect" => Ok(DownloadMode::Redirect),
+            "proxy" => Ok(DownloadMode::Proxy),
+            _ => Err(Error::UnknownDownloadMode(mode.to_owned())),
+        }
+    }
+}
+
+/// Builds the storage backend selected by the `--storage` option, encrypting
+/// tarballs if a master key is configured.
+pub fn new(opts: &StorageOpts) -> Result<Arc<dyn Storage>, Error> {
+    encrypted(backend(opts)?, &opts.encryption_opts)
+}
+
+/// Builds the storage backend selected by the `--storage` option, giving
+/// access to tarballs exactly as they're stored.
+pub fn backend(opts: &StorageOpts) -> Result<Arc<dyn Storage>, Error> {
+    match opts.storage {
+        StorageKind::Local => Ok(Arc::new(Local::new(&opts.local_opts)?)),
+        StorageKind::S3 => Ok(Arc::new(S3::new(&opts.s3_opts)?)),
+    }
+}
+
+pub(crate) fn encrypted(
+    storage: Arc<dyn Storage>,
+    opts: &EncryptionOpts,
+) -> Result<Arc<dyn Storage>, Error> {
+    match MasterKey::from_opts(opts)? {
+        Some(key) => Ok(Arc::new(Encrypted::new(
+            storage,
+            key,
+            MasterKey::old_from_opts(opts)?,
+        ))),
+        None => Ok(storage),
+    }
+}
+
+/// A storage backend given on the command line as `local:PATH` or
+/// `s3:BUCKET[/PREFIX]`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StorageLocation {
+    Local(PathBuf),
+    S3 {
+        bucket: String,
+        key_prefix: Option<String>,
+    },
+}
+
+impl StorageLocation {
+    /// Builds the storage backend, taking any other S3 settings (region,
+    /// endpoint and credentials) from `s3_opts`.
+    pub fn open(
+        &self,
+        s3_opts: &S3Opts,
+        encryption_opts: &EncryptionOpts,
+    ) -> Result<Arc<dyn Storage>, Error> {
+        encrypted(self.backend(s3_opts)?, encryption_opts)
+    }
+
+    fn backend(&self, s3_opts: &S3Opts) -> Result<Arc<dyn Storage>, Error> {
+        match *self {
+            StorageLocation::Local(ref path) => Ok(Arc::new(Local::new(&LocalOpts {
+                local_base_path: Some(path.to_path_buf()),
+            })?)),
+            StorageLocation::S3 {
+                ref bucket,
+                ref key_prefix,
+            } => {
+                let mut s3_opts = s3_opts.clone();
+                s3_opts.s3_bucket = Some(bucket.to_owned());
+                s3_opts.s3_key_prefix = key_prefix.clone();
+                Ok(Arc::new(S3::new(&s3_opts)?))
+            }
+        }
+    }
+}
+
+impl FromStr for StorageLocation {
+    type Err = Error;
+
+    fn from_str(location: &str) -> Result<Self, Self::Err> {
+        let mut parts = location.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("local"), Some(path)) if !path.is_empty() => {
+                Ok(StorageLocation::Local(PathBuf::from(path)))
+            }
+            (Some("s3"), Some(bucket)) if !bucket.is_empty() => {
+                let mut parts = bucket.splitn(2, '/');
+                Ok(StorageLocation::S3 {
+                    bucket: parts.next().unwrap_or_default().to_owned(),
+                    key_prefix: parts.next().map(|prefix| prefix.to_owned()),
+                })
+            }
+            _ => Err(Error::UnknownStorageLocation(location.to_owned())),
+        }
+    }
+}
+
+/// Computes the SHA-256 checksum of a stored tarball, in the same format as
+/// the `cksum` field of the index.
+pub fn checksum(storage: &dyn Storage, name: &str, version: &str) -> Result<String, Error> {
+    let mut object = storage.stream(name, version)?;
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = object.body.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.input(&buf[..n]);
+    }
+
+    Ok(format!("{:x}", hasher.result()))
+}
 
 pub(crate) fn crate_path(name: &str, version: &str) -> String {
     format!("crates/{}/{}-{}.crate", name, name, version)
 }
+
+/// The inverse of `crate_path`, returning the name and version of a tarball.
+pub(crate) fn parse_crate_path(path: &str) -> Option<(String, String)> {
+    let mut parts = path.trim_start_matches('/').split('/');
+
+    if parts.next()? != "crates" {
+        return None;
+    }
+
+    let name = parts.next()?;
+    let filename = parts.next()?;
+    if parts.next().is_some() {
+        return None;
+    }
+
+    let version = filename
+        .trim_end_matches(".crate")
+        .trim_start_matches(name)
+        .trim_start_matches('-');
+    if filename.len() != name.len() + version.len() + ".crate".len() + 1 || version.is_empty() {
+        return None;
+    }
+
+    Some((name.to_owned(), version.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn storage_kind_from_string() {
+        assert_eq!(StorageKind::from_str("local").unwrap(), StorageKind::Local);
+        assert_eq!(StorageKind::from_str("s3").unwrap(), StorageKind::S3);
+        assert!(StorageKind::from_str("gcs").is_err());
+    }
+
+    #[test]
+    fn download_mode_from_string() {
+        assert_eq!(
+            DownloadMode::from_str("redirect").unwrap(),
+            DownloadMode::Redirect
+        );
+        assert_eq!(
+            DownloadMode::from_str("proxy").unwrap(),
+            DownloadMode::Proxy
+        );
+        assert!(DownloadMode::from_str("mirror").is_err());
+    }
+
+    #[test]
+    fn storage_location_from_string() {
+        assert_eq!(
+            StorageLocation::from_str("local:/srv/crates").unwrap(),
+            StorageLocation::Local(PathBuf::from("/srv/crates"))
+        );
+        assert_eq!(
+            StorageLocation::from_str("s3:bucket").unwrap(),
+            StorageLocation::S3 {
+                bucket: "bucket".to_owned(),
+                key_prefix: None,
+            }
+        );
+        assert_eq!(
+            StorageLocation::from_str("s3:bucket/registry/pallet").unwrap(),
+            StorageLocation::S3 {
+                bucket: "bucket".to_owned(),
+                key_prefix: Some("registry/pallet".to_owned()),
+            }
+        );
+
+        assert!(StorageLocation::from_str("local:").is_err());
+        assert!(StorageLocation::from_str("s3").is_err());
+        assert!(StorageLocation::from_str("/srv/crates").is_err());
+    }
+
+    #[test]
+    fn crate_path_round_trip() {
+        let path = crate_path("serde_json", "1.0.0-alpha.1");
+
+        assert_eq!(
+            parse_crate_path(&path),
+            Some(("serde_json".to_owned(), "1.0.0-alpha.1".to_owned()))
+        );
+
+        assert_eq!(
+            parse_crate_path("crates/serde/serde_json-1.0.0.crate"),
+            None
+        );
+        assert_eq!(parse_crate_path("crates/serde/serde-1.0.0.tar"), None);
+        assert_eq!(parse_crate_path("crates/serde/serde.crate"), None);
+        assert_eq!(parse_crate_path("other/serde/serde-1.0.0.crate"), None);
+    }
+}
@@ -0,0 +1,385 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::Error;
+
+use super::{Object, Storage};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Put,
+    Delete,
+}
+
+/// A write to the secondary backend that failed and is waiting to be retried.
+#[derive(Clone, Debug, Serialize)]
+pub struct Pending {
+    pub name: String,
+    pub version: String,
+    pub operation: Operation,
+    pub queued_at: DateTime<Utc>,
+    pub attempts: u32,
+    pub last_error: String,
+}
+
+/// How far the secondary backend is behind the primary.
+#[derive(Debug, Serialize)]
+pub struct Lag {
+    pub pending: usize,
+    /// Seconds since the oldest pending write was queued.
+    pub lag_seconds: i64,
+    pub writes: Vec<Pending>,
+}
+
+/// Writes every tarball to a primary and a secondary backend.
+///
+/// Writes to the secondary that fail are queued and retried by `replicate`,
+/// reading the tarball back from the primary. The queue is only kept in
+/// memory, so `reconcile` rebuilds it after a restart from the objects the
+/// backends disagree on. Reads are served by the primary and fall back to the
+/// secondary when it's unavailable.
+pub struct Replicated {
+    primary: Arc<dyn Storage>,
+    secondary: Arc<dyn Storage>,
+    queue: Mutex<BTreeMap<(String, String), Pending>>,
+}
+
+impl Replicated {
+    pub fn new(primary: Arc<dyn Storage>, secondary: Arc<dyn Storage>) -> Self {
+        Replicated {
+            primary,
+            secondary,
+            queue: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn enqueue(&self, name: &str, version: &str, operation: Operation, err: Error) {
+        warn!(
+            "Failed to replicate {} {}, queued for retry: {}",
+            name, version, err
+        );
+
+        let mut queue = self.queue.lock().unwrap();
+        let pending = queue
+            .entry((name.to_owned(), version.to_owned()))
+            .or_insert_with(|| Pending {
+                name: name.to_owned(),
+                version: version.to_owned(),
+                operation,
+                queued_at: Utc::now(),
+                attempts: 0,
+                last_error: String::new(),
+            });
+        // A later write supersedes an earlier one, but the object has been
+        // out of sync since the first.
+        pending.operation = operation;
+        pending.attempts += 1;
+        pending.last_error = err.to_string();
+    }
+
+    fn write(
+        &self,
+        name: &str,
+        version: &str,
+        write: impl Fn(&dyn Storage) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        write(&*self.primary)?;
+
+        // Don't let this write race ahead of an older one that's still queued,
+        // the retry will copy whatever the primary has by then.
+        if let Some(pending) = self
+            .queue
+            .lock()
+            .unwrap()
+            .get_mut(&(name.to_owned(), version.to_owned()))
+        {
+            pending.operation = Operation::Put;
+            return Ok(());
+        }
+
+        if let Err(err) = write(&*self.secondary) {
+            self.enqueue(name, version, Operation::Put, err);
+        }
+
+        Ok(())
+    }
+
+    /// Queues a copy of every tarball the secondary is missing, which covers
+    /// any queued puts lost when the server restarted. Returns how many were
+    /// queued.
+    ///
+    /// Tarballs only the secondary has are logged but never deleted, as a
+    /// primary that's empty or misconfigured would otherwise wipe the replica.
+    pub fn reconcile(&self) -> Result<usize, Error> {
+        let primary = self.primary.list()?.into_iter().collect::<BTreeSet<_>>();
+        let secondary = self.secondary.list()?.into_iter().collect::<BTreeSet<_>>();
+
+        for (name, version) in secondary.difference(&primary) {
+            warn!(
+                "{} {} is only in the replica, delete it by hand if it isn't needed",
+                name, version
+            );
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+        let mut queued = 0;
+        for (name, version) in primary.difference(&secondary) {
+            queue
+                .entry((name.to_owned(), version.to_owned()))
+                .or_insert_with(|| {
+                    queued += 1;
+                    Pending {
+                        name: name.to_owned(),
+                        version: version.to_owned(),
+                        operation: Operation::Put,
+                        queued_at: Utc::now(),
+                        attempts: 0,
+                        last_error: "out of sync with the primary at startup".to_owned(),
+                    }
+                });
+        }
+
+        Ok(queued)
+    }
+
+    /// Retries every queued write, returning how many are still pending.
+    pub fn replicate(&self) -> usize {
+        let queued = self
+            .queue
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for pending in queued {
+            let result = match pending.operation {
+                Operation::Put => self.copy(&pending.name, &pending.version),
+                Operation::Delete => self.secondary.delete(&pending.name, &pending.version),
+            };
+
+            let mut queue = self.queue.lock().unwrap();
+            let key = (pending.name.to_owned(), pending.version.to_owned());
+            match result {
+                // Only remove the entry if it wasn't replaced in the meantime.
+                Ok(()) => {
+                    if queue.get(&key).map(|current| current.operation) == Some(pending.operation) {
+                        info!("Replicated {} {}", pending.name, pending.version);
+                        queue.remove(&key);
+                    }
+                }
+                Err(err) => {
+                    if let Some(current) = queue.get_mut(&key) {
+                        current.attempts += 1;
+                        current.last_error = err.to_string();
+                    }
+                }
+            }
+        }
+
+        self.queue.lock().unwrap().len()
+    }
+
+    fn copy(&self, name: &str, version: &str) -> Result<(), Error> {
+        // Only a queued delete removes anything from the secondary
+        if !self.primary.exists(name, version)? {
+            return Err(Error::ObjectNotFound(super::crate_path(name, version)));
+        }
+
+        let mut content = Vec::new();
+        self.primary
+            .stream(name, version)?
+            .body
+            .read_to_end(&mut content)?;
+
+        self.secondary.replace(name, version, &content)
+    }
+
+    pub fn lag(&self) -> Lag {
+        let writes = self
+            .queue
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let lag_seconds = writes
+            .iter()
+            .map(|pending| pending.queued_at)
+            .min()
+            .map(|oldest| (Utc::now() - oldest).num_seconds())
+            .unwrap_or(0);
+
+        Lag {
+            pending: writes.len(),
+            lag_seconds,
+            writes,
+        }
+    }
+
+    /// Reads from the primary, falling back to the secondary if it fails.
+    fn read<T>(&self, read: impl Fn(&dyn Storage) -> Result<T, Error>) -> Result<T, Error> {
+        read(&*self.primary).or_else(|err| {
+            warn!("Primary storage failed, reading from secondary: {}", err);
+            read(&*self.secondary)
+        })
+    }
+}
+
+impl Storage for Replicated {
+    fn put(&self, name: &str, version: &str, content: &[u8]) -> Result<(), Error> {
+        self.write(name, version, |storage| storage.put(name, version, content))
+    }
+
+    fn replace(&self, name: &str, version: &str, content: &[u8]) -> Result<(), Error> {
+        self.write(name, version, |storage| {
+            storage.replace(name, version, content)
+        })
+    }
+
+    fn get(&self, name: &str, version: &str) -> Result<String, Error> {
+        self.read(|storage| storage.get(name, version))
+    }
+
+    fn delete(&self, name: &str, version: &str) -> Result<(), Error> {
+        self.primary.delete(name, version)?;
+
+        // A queued put would otherwise fail to find the tarball for good
+        if let Some(pending) = self
+            .queue
+            .lock()
+            .unwrap()
+            .get_mut(&(name.to_owned(), version.to_owned()))
+        {
+            pending.operation = Operation::Delete;
+            return Ok(());
+        }
+
+        if let Err(err) = self.secondary.delete(name, version) {
+            self.enqueue(name, version, Operation::Delete, err);
+        }
+
+        Ok(())
+    }
+
+    fn exists(&self, name: &str, version: &str) -> Result<bool, Error> {
+        self.read(|storage| storage.exists(name, version))
+    }
+
+    fn stream(&self, name: &str, version: &str) -> Result<Object, Error> {
+        self.read(|storage| storage.stream(name, version))
+    }
+
+    fn list(&self) -> Result<Vec<(String, String)>, Error> {
+        self.read(|storage| storage.list())
+    }
+
+    fn last_modified(&self, name: &str, version: &str) -> Result<Option<DateTime<Utc>>, Error> {
+        self.read(|storage| storage.last_modified(name, version))
+    }
+
+    fn base_path(&self) -> Option<&Path> {
+        self.primary.base_path()
+    }
+}
+
+/// Reconciles the backends and then retries queued writes to the secondary
+/// every `interval` in a background thread.
+pub fn spawn_replicator(replicated: Arc<Replicated>, interval: Duration) {
+    thread::spawn(move || {
+        match replicated.reconcile() {
+            Ok(0) => {}
+            Ok(queued) => warn!("{} tarballs are out of sync with the replica", queued),
+            Err(err) => warn!("Failed to compare the replica with the primary: {}", err),
+        }
+
+        loop {
+            thread::sleep(interval);
+
+            let pending = replicated.replicate();
+            if pending > 0 {
+                warn!("{} writes still waiting to be replicated", pending);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::Memory;
+
+    #[test]
+    fn queue_failed_writes_until_secondary_recovers() {
+        let primary = Arc::new(Memory::default());
+        let secondary = Arc::new(Memory::default());
+        let replicated = Replicated::new(primary.clone(), secondary.clone());
+
+        replicated.put("foo", "1.0.0", b"foo").unwrap();
+        assert!(secondary.exists("foo", "1.0.0").unwrap());
+
+        secondary.set_unavailable(true);
+        replicated.put("bar", "1.0.0", b"bar").unwrap();
+        replicated.delete("foo", "1.0.0").unwrap();
+
+        let lag = replicated.lag();
+        assert_eq!(lag.pending, 2);
+        assert_eq!(replicated.replicate(), 2);
+
+        secondary.set_unavailable(false);
+        assert_eq!(replicated.replicate(), 0);
+        assert!(secondary.exists("bar", "1.0.0").unwrap());
+        assert!(!secondary.exists("foo", "1.0.0").unwrap());
+        assert_eq!(replicated.lag().pending, 0);
+    }
+
+    #[test]
+    fn reconcile_after_restart() {
+        let primary = Arc::new(Memory::default());
+        let secondary = Arc::new(Memory::default());
+
+        primary.put("foo", "1.0.0", b"foo").unwrap();
+        primary.put("bar", "1.0.0", b"bar").unwrap();
+        secondary.put("bar", "1.0.0", b"bar").unwrap();
+        secondary.put("baz", "1.0.0", b"baz").unwrap();
+
+        let replicated = Replicated::new(primary.clone(), secondary.clone());
+        assert_eq!(replicated.reconcile().unwrap(), 1);
+        assert_eq!(replicated.replicate(), 0);
+
+        assert!(secondary.exists("foo", "1.0.0").unwrap());
+        assert!(secondary.exists("baz", "1.0.0").unwrap());
+        assert_eq!(replicated.reconcile().unwrap(), 0);
+
+        // An empty primary leaves the replica alone
+        let replicated = Replicated::new(Arc::new(Memory::default()), secondary.clone());
+        assert_eq!(replicated.reconcile().unwrap(), 0);
+        assert_eq!(secondary.list().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn read_from_secondary_when_primary_fails() {
+        let primary = Arc::new(Memory::default());
+        let secondary = Arc::new(Memory::default());
+        let replicated = Replicated::new(primary.clone(), secondary.clone());
+
+        replicated.put("foo", "1.0.0", b"foo").unwrap();
+
+        primary.set_unavailable(true);
+        assert!(replicated.exists("foo", "1.0.0").unwrap());
+        assert_eq!(
+            crate::storage::checksum(&replicated, "foo", "1.0.0").unwrap(),
+            crate::storage::checksum(&*secondary, "foo", "1.0.0").unwrap()
+        );
+        assert!(replicated.put("bar", "1.0.0", b"bar").is_err());
+    }
+}
@@ -1,53 +1,111 @@
 use std::time::Duration;
 
-use crate::commands::Server;
+use crate::commands::S3Opts;
 use crate::error::Error;
 
-use rusoto_core::Region;
-use rusoto_credential::{AwsCredentials, StaticProvider};
+use super::{Object, Storage};
+
+use chrono::{DateTime, Utc};
+use futures::Future;
+use rusoto_core::{Region, RusotoError};
+use rusoto_credential::{
+    AwsCredentials, DefaultCredentialsProvider, ProvideAwsCredentials, StaticProvider,
+};
 use rusoto_s3::util::{PreSignedRequest, PreSignedRequestOption};
-use rusoto_s3::{GetObjectRequest, PutObjectRequest, S3Client, S3 as _};
+use rusoto_s3::{
+    DeleteObjectRequest, GetObjectRequest, HeadObjectRequest, ListObjectsV2Request,
+    PutObjectRequest, S3Client, S3 as _,
+};
+
+/// Where credentials for signing requests come from.
+enum Credentials {
+    /// Access and secret keys passed on the command line.
+    Static(StaticProvider),
+    /// Environment variables, the profile file or the instance role.
+    Chain(DefaultCredentialsProvider),
+}
+
+impl Credentials {
+    fn credentials(&self) -> Result<AwsCredentials, Error> {
+        let credentials = match *self {
+            Credentials::Static(ref provider) => provider.credentials().wait(),
+            Credentials::Chain(ref provider) => provider.credentials().wait(),
+        };
 
-#[derive(Clone)]
+        credentials.map_err(Error::Credentials)
+    }
+}
+
+/// Stores crates in an S3 bucket or an S3 compatible service such as MinIO
+/// or Ceph.
+///
+/// Requests always use path-style addressing (`endpoint/bucket/key`), which
+/// is what most S3 compatible services expect.
 pub struct S3 {
     client: S3Client,
     bucket: String,
+    key_prefix: String,
     region: Region,
-    credentials: AwsCredentials,
+    credentials: Credentials,
 }
 
 impl S3 {
-    pub fn new(server: &Server) -> Self {
-        let aws_auth = StaticProvider::new_minimal(
-            server.s3_opts.s3_access_key.to_owned(),
-            server.s3_opts.s3_secret_key.to_owned(),
-        );
+    pub fn new(opts: &S3Opts) -> Result<Self, Error> {
+        let region = match opts.s3_endpoint {
+            Some(ref endpoint) => Region::Custom {
+                name: opts
+                    .s3_region
+                    .as_ref()
+                    .map(|region| region.name().to_owned())
+                    .unwrap_or_else(|| Region::default().name().to_owned()),
+                endpoint: endpoint.trim_end_matches('/').to_owned(),
+            },
+            None => opts
+                .s3_region
+                .clone()
+                .ok_or(Error::MissingStorageOption("s3-region"))?,
+        };
+        let bucket = opts
+            .s3_bucket
+            .clone()
+            .ok_or(Error::MissingStorageOption("s3-bucket"))?;
 
         let aws_dispatcher = rusoto_core::request::HttpClient::new().unwrap();
 
-        let client = S3Client::new_with(
-            aws_dispatcher,
-            aws_auth.clone(),
-            server.s3_opts.s3_region.clone(),
-        );
-
-        let credentials = AwsCredentials::new(
-            &server.s3_opts.s3_access_key,
-            &server.s3_opts.s3_secret_key,
-            None,
-            None,
-        );
+        let (client, credentials) = match (&opts.s3_access_key, &opts.s3_secret_key) {
+            (Some(access_key), Some(secret_key)) => {
+                let provider =
+                    StaticProvider::new_minimal(access_key.to_owned(), secret_key.to_owned());
+                let client = S3Client::new_with(aws_dispatcher, provider.clone(), region.clone());
+                (client, Credentials::Static(provider))
+            }
+            (Some(_), None) => return Err(Error::MissingStorageOption("s3-secret-key")),
+            (None, Some(_)) => return Err(Error::MissingStorageOption("s3-access-key")),
+            (None, None) => {
+                // Clones share the cached credentials, so they're only looked up once
+                let provider = DefaultCredentialsProvider::new().map_err(Error::Credentials)?;
+                let client = S3Client::new_with(aws_dispatcher, provider.clone(), region.clone());
+                (client, Credentials::Chain(provider))
+            }
+        };
 
-        S3 {
+        Ok(S3 {
             client,
-            bucket: server.s3_opts.s3_bucket.to_owned(),
-            region: server.s3_opts.s3_region.clone(),
+            bucket,
+            key_prefix: key_prefix(opts.s3_key_prefix.as_ref().map(|x| &**x)),
+            region,
             credentials,
-        }
+        })
     }
 
-    pub fn put(&self, name: &str, version: &str, content: &[u8]) -> Result<(), Error> {
-        let key = super::crate_path(name, version);
+    fn key(&self, name: &str, version: &str) -> String {
+        format!("{}{}", self.key_prefix, super::crate_path(name, version))
+    }
+}
+
+impl Storage for S3 {
+    fn put(&self, name: &str, version: &str, content: &[u8]) -> Result<(), Error> {
+        let key = self.key(name, version);
 
         self.client
             .put_object(PutObjectRequest {
@@ -62,8 +120,13 @@ impl S3 {
         Ok(())
     }
 
-    pub fn get(&self, name: &str, version: &str) -> Result<String, Error> {
-        let key = super::crate_path(name, version);
+    fn replace(&self, name: &str, version: &str, content: &[u8]) -> Result<(), Error> {
+        // Objects are replaced atomically by S3
+        self.put(name, version, content)
+    }
+
+    fn get(&self, name: &str, version: &str) -> Result<String, Error> {
+        let key = self.key(name, version);
 
         let req = GetObjectRequest {
             bucket: self.bucket.to_owned(),
@@ -73,10 +136,153 @@ impl S3 {
 
         Ok(req.get_presigned_url(
             &self.region,
-            &self.credentials,
+            &self.credentials.credentials()?,
             &PreSignedRequestOption {
                 expires_in: Duration::from_secs(10), // TODO: Make configurable
             },
         ))
     }
+
+    fn delete(&self, name: &str, version: &str) -> Result<(), Error> {
+        let key = self.key(name, version);
+
+        self.client
+            .delete_object(DeleteObjectRequest {
+                bucket: self.bucket.to_owned(),
+                key,
+                ..Default::default()
+            })
+            .with_timeout(Duration::from_secs(10))
+            .sync()
+            .map_err(Error::DeleteS3)?;
+        Ok(())
+    }
+
+    fn exists(&self, name: &str, version: &str) -> Result<bool, Error> {
+        let key = self.key(name, version);
+
+        let result = self
+            .client
+            .head_object(HeadObjectRequest {
+                bucket: self.bucket.to_owned(),
+                key,
+                ..Default::default()
+            })
+            .with_timeout(Duration::from_secs(10))
+            .sync();
+
+        match result {
+            Ok(_) => Ok(true),
+            // HEAD responses have no body, so a missing key surfaces as an
+            // unknown error rather than `NoSuchKey`.
+            Err(RusotoError::Unknown(ref resp)) if resp.status.as_u16() == 404 => Ok(false),
+            Err(err) => Err(Error::HeadS3(err)),
+        }
+    }
+
+    fn stream(&self, name: &str, version: &str) -> Result<Object, Error> {
+        let key = self.key(name, version);
+
+        let output = self
+            .client
+            .get_object(GetObjectRequest {
+                bucket: self.bucket.to_owned(),
+                key: key.to_owned(),
+                ..Default::default()
+            })
+            .sync()
+            .map_err(Error::DownloadS3)?;
+
+        // Downloads are sent with this length, so guessing would truncate them
+        let length = match output.content_length {
+            Some(length) if length >= 0 => length as u64,
+            _ => return Err(Error::MissingContentLength(key)),
+        };
+        let body = output.body.ok_or(Error::ObjectNotFound(key))?;
+
+        Ok(Object {
+            length,
+            body: Box::new(body.into_blocking_read()),
+        })
+    }
+
+    fn last_modified(&self, name: &str, version: &str) -> Result<Option<DateTime<Utc>>, Error> {
+        let key = self.key(name, version);
+
+        let result = self
+            .client
+            .head_object(HeadObjectRequest {
+                bucket: self.bucket.to_owned(),
+                key,
+                ..Default::default()
+            })
+            .with_timeout(Duration::from_secs(10))
+            .sync();
+
+        match result {
+            Ok(output) => Ok(output
+                .last_modified
+                .and_then(|last_modified| DateTime::parse_from_rfc2822(&last_modified).ok())
+                .map(|last_modified| last_modified.with_timezone(&Utc))),
+            Err(RusotoError::Unknown(ref resp)) if resp.status.as_u16() == 404 => Ok(None),
+            Err(err) => Err(Error::HeadS3(err)),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<(String, String)>, Error> {
+        let mut crates = Vec::new();
+        let mut continuation_token = None;
+
+        loop {
+            let output = self
+                .client
+                .list_objects_v2(ListObjectsV2Request {
+                    bucket: self.bucket.to_owned(),
+                    prefix: Some(format!("{}crates/", self.key_prefix)),
+                    continuation_token,
+                    ..Default::default()
+                })
+                .with_timeout(Duration::from_secs(30))
+                .sync()
+                .map_err(Error::ListS3)?;
+
+            for object in output.contents.unwrap_or_default() {
+                let key = object.key.unwrap_or_default();
+                if let Some(krate) = super::parse_crate_path(&key[self.key_prefix.len()..]) {
+                    crates.push(krate);
+                }
+            }
+
+            match output.next_continuation_token {
+                Some(token) if output.is_truncated.unwrap_or(false) => {
+                    continuation_token = Some(token)
+                }
+                _ => break,
+            }
+        }
+
+        Ok(crates)
+    }
+}
+
+/// Normalises a key prefix so it can be prepended to `crate_path`.
+fn key_prefix(prefix: Option<&str>) -> String {
+    match prefix.map(|prefix| prefix.trim_matches('/')) {
+        Some(prefix) if !prefix.is_empty() => format!("{}/", prefix),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalise_key_prefix() {
+        assert_eq!(key_prefix(None), "");
+        assert_eq!(key_prefix(Some("")), "");
+        assert_eq!(key_prefix(Some("/")), "");
+        assert_eq!(key_prefix(Some("registry")), "registry/");
+        assert_eq!(key_prefix(Some("/registry/pallet/")), "registry/pallet/");
+    }
 }
@@ -5,3 +5,8 @@ pub fn generate_token() -> String {
         .filter(|x| *x != '-')
         .collect::<String>()
 }
+
+/// Whether a value has the format of the tokens `generate_token` makes.
+pub fn is_token(value: &str) -> bool {
+    value.len() == 32 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
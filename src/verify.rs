@@ -0,0 +1,238 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Read};
+
+use crate::error::Error;
+use crate::metadata::Metadata;
+use crate::storage::{self, Storage};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Entry {
+    pub name: String,
+    pub vers: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Corrupted {
+    pub name: String,
+    pub vers: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// The outcome of comparing the index against a storage backend.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub checked: usize,
+    pub missing: Vec<Entry>,
+    pub corrupted: Vec<Corrupted>,
+    pub unexpected: Vec<Entry>,
+    pub repaired: Vec<Entry>,
+}
+
+impl Report {
+    /// Whether every index entry has an intact tarball in storage. Unexpected
+    /// objects don't affect this as they don't break any downloads.
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupted.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in &self.missing {
+            writeln!(f, "missing: {} {}", entry.name, entry.vers)?;
+        }
+        for entry in &self.corrupted {
+            writeln!(
+                f,
+                "corrupted: {} {} (expected {}, found {})",
+                entry.name, entry.vers, entry.expected, entry.actual
+            )?;
+        }
+        for entry in &self.unexpected {
+            writeln!(f, "unexpected: {} {}", entry.name, entry.vers)?;
+        }
+        for entry in &self.repaired {
+            writeln!(f, "repaired: {} {}", entry.name, entry.vers)?;
+        }
+        writeln!(
+            f,
+            "checked {} crates: {} missing, {} corrupted, {} unexpected, {} repaired",
+            self.checked,
+            self.missing.len(),
+            self.corrupted.len(),
+            self.unexpected.len(),
+            self.repaired.len()
+        )
+    }
+}
+
+/// Hashes the tarball of every index entry through the storage backend and
+/// compares it against the checksum in the index.
+///
+/// Missing or corrupted tarballs are restored from `repair_from` when given,
+/// as long as the copy there matches the index checksum.
+pub fn verify_storage(
+    entries: &[Metadata],
+    storage: &dyn Storage,
+    repair_from: Option<&dyn Storage>,
+) -> Result<Report, Error> {
+    let mut report = Report::default();
+
+    for metadata in entries {
+        let name = metadata.name.to_string();
+        let vers = metadata.vers.to_string();
+
+        report.checked += 1;
+
+        let actual = if storage.exists(&name, &vers)? {
+            let actual = match storage::checksum(storage, &name, &vers) {
+                Ok(actual) => actual,
+                // Backends that verify tarballs as they're read refuse to
+                // return one that doesn't match its recorded checksum
+                Err(Error::IO(ref err)) if err.kind() == io::ErrorKind::InvalidData => {
+                    match err
+                        .get_ref()
+                        .and_then(|err| err.downcast_ref::<storage::Corrupted>())
+                    {
+                        Some(storage::Corrupted { actual, .. }) => actual.to_owned(),
+                        None => err.to_string(),
+                    }
+                }
+                Err(err) => return Err(err),
+            };
+            if actual == metadata.cksum {
+                continue;
+            }
+            Some(actual)
+        } else {
+            None
+        };
+
+        if let Some(source) = repair_from {
+            if repair(metadata, source, storage)? {
+                info!("Repaired {} {}", name, vers);
+                report.repaired.push(Entry { name, vers });
+                continue;
+            }
+        }
+
+        match actual {
+            Some(actual) => report.corrupted.push(Corrupted {
+                name,
+                vers,
+                expected: metadata.cksum.to_owned(),
+                actual,
+            }),
+            None => report.missing.push(Entry { name, vers }),
+        }
+    }
+
+    let indexed = entries
+        .iter()
+        .map(|metadata| (metadata.name.to_string(), metadata.vers.to_string()))
+        .collect::<HashSet<_>>();
+
+    let mut unexpected = storage
+        .list()?
+        .into_iter()
+        .filter(|krate| !indexed.contains(krate))
+        .map(|(name, vers)| Entry { name, vers })
+        .collect::<Vec<_>>();
+    unexpected.sort_by(|a, b| (&a.name, &a.vers).cmp(&(&b.name, &b.vers)));
+    report.unexpected = unexpected;
+
+    Ok(report)
+}
+
+/// Copies a tarball from `source` to `target` if the copy in `source` matches
+/// the index checksum.
+fn repair(metadata: &Metadata, source: &dyn Storage, target: &dyn Storage) -> Result<bool, Error> {
+    let name = metadata.name.to_string();
+    let vers = metadata.vers.to_string();
+
+    if !source.exists(&name, &vers)? {
+        return Ok(false);
+    }
+
+    let mut content = Vec::new();
+    source
+        .stream(&name, &vers)?
+        .body
+        .read_to_end(&mut content)?;
+
+    if format!("{:x}", Sha256::digest(&content)) != metadata.cksum {
+        return Ok(false);
+    }
+
+    target.replace(&name, &vers, &content)?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::entry;
+    use crate::storage::memory::Memory;
+
+    #[test]
+    fn report_missing_corrupted_and_unexpected() {
+        let storage = Memory::default();
+        storage.put("foo", "1.0.0", b"foo").unwrap();
+        storage.put("bar", "1.0.0", b"corrupted").unwrap();
+        storage.put("baz", "1.0.0", b"baz").unwrap();
+
+        let entries = vec![
+            entry("foo", "1.0.0", b"foo"),
+            entry("bar", "1.0.0", b"bar"),
+            entry("qux", "1.0.0", b"qux"),
+        ];
+
+        let report = verify_storage(&entries, &storage, None).unwrap();
+
+        assert!(!report.is_ok());
+        assert_eq!(report.checked, 3);
+        assert_eq!(
+            report.missing,
+            vec![Entry {
+                name: "qux".to_owned(),
+                vers: "1.0.0".to_owned()
+            }]
+        );
+        assert_eq!(report.corrupted.len(), 1);
+        assert_eq!(report.corrupted[0].name, "bar");
+        assert_eq!(
+            report.unexpected,
+            vec![Entry {
+                name: "baz".to_owned(),
+                vers: "1.0.0".to_owned()
+            }]
+        );
+    }
+
+    #[test]
+    fn repair_from_another_backend() {
+        let storage = Memory::default();
+        storage.put("bar", "1.0.0", b"corrupted").unwrap();
+
+        let source = Memory::default();
+        source.put("bar", "1.0.0", b"bar").unwrap();
+        source.put("qux", "1.0.0", b"qux").unwrap();
+
+        let entries = vec![entry("bar", "1.0.0", b"bar"), entry("qux", "1.0.0", b"qux")];
+
+        let report = verify_storage(&entries, &storage, Some(&source)).unwrap();
+
+        assert!(report.is_ok());
+        assert_eq!(report.repaired.len(), 2);
+        assert_eq!(
+            storage::checksum(&storage, "bar", "1.0.0").unwrap(),
+            entries[0].cksum
+        );
+    }
+}